@@ -0,0 +1,136 @@
+//! Bandwidth admission control of scheduling contexts, enabled by the `sc_admission` feature.
+//!
+//! The utilisation of an SC is `budget / period` in units of `UTILISATION_FULL`, rounded up,
+//! and 0 for a round robin SC. `sched_context_configure` calls `sc_admit` before it changes
+//! the refills of an SC. It accounts the utilisation of the SC to a core and a domain, and
+//! rejects the configuration if the total utilisation of the core would exceed
+//! `UTILISATION_FULL`, or that of the domain on the core its cap. The SC keeps the utilisation
+//! it was admitted with, which `sc_release_admission` gives back when its TCB is unbound, when
+//! it is deleted and before it moves to another core.
+
+use sel4_common::platform::time_def::ticks_t;
+use sel4_common::sel4_config::{CONFIG_MAX_NUM_NODES, CONFIG_NUM_DOMAINS};
+
+use crate::sched_context::sched_context_t;
+
+/// The utilisation of a core running SCs all the time, i.e. 100%.
+pub const UTILISATION_FULL: ticks_t = 1_000_000;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+/// Reasons for rejecting a scheduling context configuration in `sc_admit`.
+pub enum AdmissionError {
+    /// The core is `>= CONFIG_MAX_NUM_NODES` or the domain `>= CONFIG_NUM_DOMAINS`.
+    InvalidTarget,
+    /// The utilisation of the core would exceed `UTILISATION_FULL`.
+    CoreOverload,
+    /// The utilisation of the domain on the core would exceed its cap.
+    DomainOverload,
+}
+
+/// The utilisation of the admitted SCs of each domain on each core.
+pub static mut ksSCUtilisation: [[ticks_t; CONFIG_NUM_DOMAINS]; CONFIG_MAX_NUM_NODES] =
+    [[0; CONFIG_NUM_DOMAINS]; CONFIG_MAX_NUM_NODES];
+
+/// The maximum utilisation of each domain on any core.
+pub static mut ksDomainUtilisationCap: [ticks_t; CONFIG_NUM_DOMAINS] =
+    [UTILISATION_FULL; CONFIG_NUM_DOMAINS];
+
+/// Get the utilisation of an SC with `budget` every `period`, 0 for a round robin SC.
+pub fn sc_utilisation(budget: ticks_t, period: ticks_t) -> ticks_t {
+    if period == 0 {
+        return 0;
+    }
+    (budget as u128 * UTILISATION_FULL as u128).div_ceil(period as u128) as ticks_t
+}
+
+/// Get the utilisation of the admitted SCs on `core`.
+pub fn core_utilisation(core: usize) -> ticks_t {
+    unsafe { ksSCUtilisation[core].iter().sum() }
+}
+
+/// Get the utilisation of the admitted SCs of `dom` on `core`.
+pub fn domain_utilisation(core: usize, dom: usize) -> ticks_t {
+    unsafe { ksSCUtilisation[core][dom] }
+}
+
+/// Get the utilisation that can still be admitted for `dom` on `core`.
+pub fn remaining_capacity(core: usize, dom: usize) -> ticks_t {
+    let core_left = UTILISATION_FULL.saturating_sub(core_utilisation(core));
+    let domain_left =
+        unsafe { ksDomainUtilisationCap[dom] }.saturating_sub(domain_utilisation(core, dom));
+    core_left.min(domain_left)
+}
+
+/// Cap the utilisation of `dom` on every core.
+///
+/// The cap is rejected if it is above `UTILISATION_FULL` or below the utilisation already
+/// admitted for the domain on some core.
+pub fn set_domain_utilisation_cap(dom: usize, cap: ticks_t) -> Result<(), AdmissionError> {
+    if dom >= CONFIG_NUM_DOMAINS || cap > UTILISATION_FULL {
+        return Err(AdmissionError::InvalidTarget);
+    }
+    if (0..CONFIG_MAX_NUM_NODES).any(|core| domain_utilisation(core, dom) > cap) {
+        return Err(AdmissionError::DomainOverload);
+    }
+    unsafe { ksDomainUtilisationCap[dom] = cap };
+    Ok(())
+}
+
+impl sched_context_t {
+    /// Admit the SC with `budget` every `period` on `core` for `dom`, replacing its current
+    /// utilisation if it is admitted already. It is called by `sched_context_configure`.
+    pub fn sc_admit(
+        &mut self,
+        core: usize,
+        dom: usize,
+        budget: ticks_t,
+        period: ticks_t,
+    ) -> Result<(), AdmissionError> {
+        if core >= CONFIG_MAX_NUM_NODES || dom >= CONFIG_NUM_DOMAINS {
+            return Err(AdmissionError::InvalidTarget);
+        }
+        // the current utilisation of the SC is replaced if it is accounted to the same place
+        let old = match self.scAdmitted {
+            true => self.scAdmittedUtilisation as ticks_t,
+            false => 0,
+        };
+        let (old_core, old_domain) = match self.scAdmitted && self.scAdmittedCore as usize == core {
+            true => (
+                old,
+                if self.scAdmittedDomain as usize == dom {
+                    old
+                } else {
+                    0
+                },
+            ),
+            false => (0, 0),
+        };
+        let new = sc_utilisation(budget, period);
+        if core_utilisation(core) - old_core + new > UTILISATION_FULL {
+            return Err(AdmissionError::CoreOverload);
+        }
+        if domain_utilisation(core, dom) - old_domain + new > unsafe { ksDomainUtilisationCap[dom] }
+        {
+            return Err(AdmissionError::DomainOverload);
+        }
+        self.sc_release_admission();
+        unsafe { ksSCUtilisation[core][dom] += new };
+        self.scAdmittedCore = core as u8;
+        self.scAdmittedDomain = dom as u8;
+        self.scAdmittedUtilisation = new as u32;
+        self.scAdmitted = true;
+        Ok(())
+    }
+
+    /// Release the utilisation accounted for the SC
+    pub fn sc_release_admission(&mut self) {
+        if self.scAdmitted {
+            unsafe {
+                ksSCUtilisation[self.scAdmittedCore as usize][self.scAdmittedDomain as usize] -=
+                    self.scAdmittedUtilisation as ticks_t
+            };
+            self.scAdmittedUtilisation = 0;
+            self.scAdmitted = false;
+        }
+    }
+}
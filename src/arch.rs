@@ -0,0 +1,15 @@
+//! The architecture, timer and vspace interface used by this crate.
+//!
+//! The scheduler only reaches the hardware through this module, so that the `host_sim`
+//! feature can replace it with the simulated implementation in `host_sim::arch`.
+#![allow(unused_imports)]
+
+#[cfg(not(feature = "host_sim"))]
+pub(crate) use sel4_common::arch::*;
+#[cfg(not(feature = "host_sim"))]
+pub(crate) use sel4_common::platform::{timer, Timer_func};
+#[cfg(not(feature = "host_sim"))]
+pub(crate) use sel4_vspace::set_vm_root;
+
+#[cfg(feature = "host_sim")]
+pub(crate) use crate::host_sim::arch::*;
@@ -0,0 +1,90 @@
+//! Per-thread CPU utilisation accounting for the non-MCS kernel, enabled by the
+//! `benchmark_track_utilisation` feature.
+//!
+//! Time is measured in timer ticks: `timer_tick` charges each tick to the current thread
+//! and to the core, and `switch_to_this` counts schedules and preemptions. The idle thread
+//! of each core is accounted like any other thread, so its counters are the idle time of
+//! that core. The query and reset functions follow the seL4 benchmark utilisation interface.
+
+use sel4_common::utils::convert_to_mut_type_ref;
+
+use crate::{get_currenct_thread, get_idle_thread, tcb_t, NODE_STATE, NODE_STATE_ON_CORE};
+use crate::{SET_NODE_STATE, SET_NODE_STATE_ON_CORE};
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+/// Utilisation counters of a TCB.
+pub struct benchmark_util_t {
+    /// Ticks the thread has run for.
+    pub utilisation: usize,
+    /// Number of times the thread has been switched to.
+    pub number_schedules: usize,
+    /// Number of times the thread has been switched away from while still runnable.
+    pub number_preemptions: usize,
+}
+
+/// Indices of the words filled in by `benchmark_get_thread_utilisation`.
+pub const BENCHMARK_TCB_UTILISATION: usize = 0;
+pub const BENCHMARK_TCB_NUMBER_SCHEDULES: usize = 1;
+pub const BENCHMARK_TCB_NUMBER_PREEMPTIONS: usize = 2;
+pub const BENCHMARK_IDLE_LOCALCPU_UTILISATION: usize = 3;
+pub const BENCHMARK_IDLE_TCBCPU_UTILISATION: usize = 4;
+pub const BENCHMARK_IDLE_NUMBER_SCHEDULES: usize = 5;
+pub const BENCHMARK_TOTAL_UTILISATION: usize = 6;
+/// Number of words filled in by `benchmark_get_thread_utilisation`.
+pub const BENCHMARK_UTILISATION_WORDS: usize = 7;
+
+#[inline]
+/// Charge `ticks` timer ticks to the current thread and to the current core.
+pub fn benchmark_utilisation_tick(ticks: usize) {
+    get_currenct_thread().benchmark.utilisation += ticks;
+    SET_NODE_STATE!(ksBenchmarkTotalTicks = NODE_STATE!(ksBenchmarkTotalTicks) + ticks);
+}
+
+#[inline]
+/// Account a switch from the current thread to `next`.
+pub fn benchmark_utilisation_switch(next: &mut tcb_t) {
+    let cur = NODE_STATE!(ksCurThread);
+    if cur == next.get_ptr() {
+        return;
+    }
+    // ksCurThread is not set yet while the kernel boots
+    if cur != 0 {
+        let current = get_currenct_thread();
+        if current.is_runnable() && cur != NODE_STATE!(ksIdleThread) {
+            current.benchmark.number_preemptions += 1;
+        }
+    }
+    next.benchmark.number_schedules += 1;
+}
+
+/// Get the utilisation of `tcb`, indexed by the `BENCHMARK_*` constants.
+///
+/// The idle and total counters are those of the current core and of the core `tcb` runs on.
+pub fn benchmark_get_thread_utilisation(tcb: &tcb_t) -> [usize; BENCHMARK_UTILISATION_WORDS] {
+    let _cpu = tcb.get_cpu();
+    let local_idle = get_idle_thread();
+    let tcb_idle = convert_to_mut_type_ref::<tcb_t>(NODE_STATE_ON_CORE!(_cpu, ksIdleThread));
+    let mut buffer = [0; BENCHMARK_UTILISATION_WORDS];
+    buffer[BENCHMARK_TCB_UTILISATION] = tcb.benchmark.utilisation;
+    buffer[BENCHMARK_TCB_NUMBER_SCHEDULES] = tcb.benchmark.number_schedules;
+    buffer[BENCHMARK_TCB_NUMBER_PREEMPTIONS] = tcb.benchmark.number_preemptions;
+    buffer[BENCHMARK_IDLE_LOCALCPU_UTILISATION] = local_idle.benchmark.utilisation;
+    buffer[BENCHMARK_IDLE_TCBCPU_UTILISATION] = tcb_idle.benchmark.utilisation;
+    buffer[BENCHMARK_IDLE_NUMBER_SCHEDULES] = tcb_idle.benchmark.number_schedules;
+    buffer[BENCHMARK_TOTAL_UTILISATION] = NODE_STATE_ON_CORE!(_cpu, ksBenchmarkTotalTicks);
+    buffer
+}
+
+#[inline]
+/// Reset the utilisation counters of `tcb`.
+pub fn benchmark_reset_thread_utilisation(tcb: &mut tcb_t) {
+    tcb.benchmark = benchmark_util_t::default();
+}
+
+/// Reset the idle and total counters of the given core.
+pub fn benchmark_reset_core_utilisation(_cpu: usize) {
+    let idle = convert_to_mut_type_ref::<tcb_t>(NODE_STATE_ON_CORE!(_cpu, ksIdleThread));
+    benchmark_reset_thread_utilisation(idle);
+    SET_NODE_STATE_ON_CORE!(_cpu, ksBenchmarkTotalTicks = 0);
+}
@@ -0,0 +1,110 @@
+//! The debug TCB registry, enabled by the `debug_build` feature.
+//!
+//! Every live TCB is kept in the `ksDebugTCBs` list of its core, like seL4 does in debug
+//! builds. The links and the name of a TCB live in a `debug_tcb_t` placed in the spare space
+//! of the TCB object, right below the `tcb_t`.
+
+use core::mem::size_of;
+
+use sel4_common::sel4_config::{CONFIG_MAX_NUM_NODES, TCB_OFFSET};
+use sel4_common::utils::convert_to_mut_type_ref;
+use sel4_cspace::interface::cte_t;
+
+use crate::tcb_queue::{link_from_ptr, link_to_ptr, tcb_link_t, TcbLink, TcbQueueIter};
+use crate::{tcb_t, NODE_STATE_ON_CORE, SET_NODE_STATE_ON_CORE};
+
+/// Maximum length of a thread name, including the terminating NUL.
+pub const TCB_NAME_LENGTH: usize = 64;
+
+#[repr(C)]
+/// The debug data of a TCB, stored in the TCB object in front of the `tcb_t`.
+pub struct debug_tcb_t {
+    /// The next TCB in the debug list of the core
+    pub tcbDebugNext: usize,
+    /// The previous TCB in the debug list of the core
+    pub tcbDebugPrev: usize,
+    /// The NUL terminated name of the TCB
+    pub tcbName: [u8; TCB_NAME_LENGTH],
+}
+
+/// Number of CNode slots at the start of a TCB object, `tcbCNodeEntries` in seL4.
+const TCB_CNODE_ENTRIES: usize = 5;
+
+// the debug data sits between the CNode slots and the tcb_t, it must not overlap either
+const _: () =
+    assert!(TCB_CNODE_ENTRIES * size_of::<cte_t>() + size_of::<debug_tcb_t>() <= TCB_OFFSET);
+
+/// The `tcbDebugNext`/`tcbDebugPrev` links, used by the `ksDebugTCBs` lists.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct DebugLink;
+
+impl TcbLink for DebugLink {
+    #[inline]
+    fn next(tcb: &tcb_t) -> tcb_link_t {
+        link_from_ptr(tcb.debug_tcb().tcbDebugNext)
+    }
+    #[inline]
+    fn prev(tcb: &tcb_t) -> tcb_link_t {
+        link_from_ptr(tcb.debug_tcb().tcbDebugPrev)
+    }
+    #[inline]
+    fn set_next(tcb: &mut tcb_t, next: tcb_link_t) {
+        tcb.debug_tcb().tcbDebugNext = link_to_ptr(next);
+    }
+    #[inline]
+    fn set_prev(tcb: &mut tcb_t, prev: tcb_link_t) {
+        tcb.debug_tcb().tcbDebugPrev = link_to_ptr(prev);
+    }
+}
+
+impl tcb_t {
+    #[inline]
+    /// Get the debug data stored in the TCB object
+    pub fn debug_tcb(&self) -> &'static mut debug_tcb_t {
+        convert_to_mut_type_ref::<debug_tcb_t>(self.get_ptr() - size_of::<debug_tcb_t>())
+    }
+
+    /// Set the name of the TCB, truncated to `TCB_NAME_LENGTH - 1` bytes
+    pub fn set_name(&mut self, name: &str) {
+        let mut len = name.len().min(TCB_NAME_LENGTH - 1);
+        while !name.is_char_boundary(len) {
+            len -= 1;
+        }
+        let buf = &mut self.debug_tcb().tcbName;
+        buf[..len].copy_from_slice(&name.as_bytes()[..len]);
+        buf[len..].fill(0);
+    }
+
+    /// Get the name of the TCB, empty if it has none
+    pub fn get_name(&self) -> &'static str {
+        let buf = &self.debug_tcb().tcbName;
+        let len = buf.iter().position(|&c| c == 0).unwrap_or(TCB_NAME_LENGTH);
+        core::str::from_utf8(&buf[..len]).unwrap_or("")
+    }
+
+    /// Add the TCB to the debug list of its core
+    pub fn debug_append(&mut self) {
+        let mut list = NODE_STATE_ON_CORE!(self.tcbAffinity, ksDebugTCBs);
+        list.push_front(self);
+        SET_NODE_STATE_ON_CORE!(self.tcbAffinity, ksDebugTCBs = list);
+    }
+
+    /// Remove the TCB from the debug list of its core
+    pub fn debug_remove(&mut self) {
+        let mut list = NODE_STATE_ON_CORE!(self.tcbAffinity, ksDebugTCBs);
+        list.unlink(self);
+        SET_NODE_STATE_ON_CORE!(self.tcbAffinity, ksDebugTCBs = list);
+    }
+}
+
+/// Iterate over the live TCBs of `cpu`
+pub fn debug_tcbs(_cpu: usize) -> TcbQueueIter<DebugLink> {
+    NODE_STATE_ON_CORE!(_cpu, ksDebugTCBs).iter()
+}
+
+/// Find a live TCB by name, on any core
+pub fn find_tcb_by_name(name: &str) -> Option<&'static tcb_t> {
+    (0..CONFIG_MAX_NUM_NODES)
+        .flat_map(debug_tcbs)
+        .find(|tcb| tcb.get_name() == name)
+}
@@ -4,6 +4,9 @@ use sel4_common::{
     sel4_config::{CONFIG_MAX_NUM_NODES, SEL4_TCB_BITS},
     BIT,
 };
+
+#[cfg(feature = "have_fpu")]
+use crate::tcb_t;
 #[repr(align(2048))]
 pub struct ksIdleThreadTCB_data {
     pub data: [[u8; BIT!(SEL4_TCB_BITS)]; CONFIG_MAX_NUM_NODES],
@@ -31,4 +34,16 @@ pub static mut ksIdleThreadSC: ksIdleThreadSC_data = ksIdleThreadSC_data {
 extern "C" {
     #[cfg(feature = "enable_smp")]
     pub fn do_mask_reschedule(mask: usize);
+    #[cfg(feature = "enable_smp")]
+    pub fn do_remote_stall(cpu: usize);
+    #[cfg(feature = "have_fpu")]
+    pub fn enable_fpu();
+    #[cfg(feature = "have_fpu")]
+    pub fn disable_fpu();
+    #[cfg(feature = "have_fpu")]
+    pub fn save_fpu_state(tcb: &mut tcb_t);
+    #[cfg(feature = "have_fpu")]
+    pub fn load_fpu_state(tcb: &tcb_t);
+    #[cfg(all(feature = "enable_smp", feature = "have_fpu"))]
+    pub fn remote_switch_fpu_owner(new_owner: usize, cpu: usize);
 }
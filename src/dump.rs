@@ -0,0 +1,202 @@
+//! Dumping of all threads through `log`, for debug syscalls and the panic path.
+//!
+//! With `debug_build` the threads come from the debug registry, which holds every live TCB.
+//! Otherwise they come from the current, idle and queued threads of each core, which misses
+//! the threads blocked on endpoints and notifications. The dump only reads kernel state, and
+//! it bounds every list walk and never panics on corrupted values.
+
+use log::info;
+#[cfg(feature = "kernel_mcs")]
+use sel4_common::platform::time_def::ticks_t;
+#[cfg(feature = "enable_smp")]
+use sel4_common::sel4_config::CONFIG_MAX_NUM_NODES;
+#[cfg(not(feature = "debug_build"))]
+use sel4_common::sel4_config::NUM_READY_QUEUES;
+#[cfg(feature = "kernel_mcs")]
+use sel4_common::utils::{convert_to_mut_type_ref, convert_to_option_mut_type_ref};
+
+#[cfg(feature = "debug_build")]
+use crate::debug_tcbs;
+#[cfg(feature = "kernel_mcs")]
+use crate::reply::reply_t;
+#[cfg(feature = "kernel_mcs")]
+use crate::sched_context::sched_context_t;
+use crate::scheduler::thread_name;
+use crate::{get_time_slice_band, tcb_t, time_slice_band, TIME_SLICE_BANDS};
+#[cfg(not(feature = "debug_build"))]
+use crate::{
+    tcb_queue_t, SchedLink, NODE_STATE_ON_CORE, SCHEDULER_ACTION_CHOOSE_NEW_THREAD,
+    SCHEDULER_ACTION_RESUME_CURRENT_THREAD,
+};
+#[cfg(not(feature = "debug_build"))]
+use sel4_common::utils::convert_to_type_ref;
+
+/// Maximum number of entries followed in one list, so that a corrupted list cannot hang the dump.
+const DUMP_LIST_LIMIT: usize = 4096;
+
+#[no_mangle]
+/// Log every thread of every core.
+///
+/// Without `debug_build` the threads blocked on endpoints and notifications are not logged,
+/// because no kernel list holds them.
+pub fn dump_all_threads() {
+    #[cfg(feature = "enable_smp")]
+    for cpu in 0..CONFIG_MAX_NUM_NODES {
+        dump_threads_on_core(cpu);
+    }
+    #[cfg(not(feature = "enable_smp"))]
+    dump_threads_on_core(0);
+}
+
+#[cfg(feature = "debug_build")]
+fn dump_threads_on_core(cpu: usize) {
+    info!("threads on cpu {}:", cpu);
+    for tcb in debug_tcbs(cpu).take(DUMP_LIST_LIMIT) {
+        dump_thread(tcb);
+    }
+}
+
+#[cfg(not(feature = "debug_build"))]
+fn dump_threads_on_core(_cpu: usize) {
+    info!("threads on cpu {}:", _cpu);
+    let current = NODE_STATE_ON_CORE!(_cpu, ksCurThread);
+    let idle = NODE_STATE_ON_CORE!(_cpu, ksIdleThread);
+    let action = NODE_STATE_ON_CORE!(_cpu, ksSchedulerAction);
+    if current != 0 {
+        dump_thread(convert_to_type_ref::<tcb_t>(current));
+    }
+    if idle != 0 && idle != current {
+        dump_thread(convert_to_type_ref::<tcb_t>(idle));
+    }
+    if action != SCHEDULER_ACTION_RESUME_CURRENT_THREAD
+        && action != SCHEDULER_ACTION_CHOOSE_NEW_THREAD
+    {
+        dump_thread(convert_to_type_ref::<tcb_t>(action));
+    }
+    for index in 0..NUM_READY_QUEUES {
+        dump_queue(&ready_queue_on_core(_cpu, index));
+    }
+    #[cfg(all(feature = "kernel_mcs", not(feature = "release_heap")))]
+    dump_queue(&NODE_STATE_ON_CORE!(_cpu, ksReleaseQueue));
+    #[cfg(feature = "release_heap")]
+    for tcb in NODE_STATE_ON_CORE!(_cpu, ksReleaseQueue)
+        .release_heap_iter()
+        .take(DUMP_LIST_LIMIT)
+    {
+        dump_thread(tcb);
+    }
+}
+
+#[cfg(not(feature = "debug_build"))]
+fn ready_queue_on_core(_cpu: usize, index: usize) -> tcb_queue_t<SchedLink> {
+    unsafe {
+        #[cfg(feature = "enable_smp")]
+        {
+            crate::ksSMP[_cpu].ksReadyQueues[index]
+        }
+        #[cfg(not(feature = "enable_smp"))]
+        {
+            crate::ksReadyQueues[index]
+        }
+    }
+}
+
+#[cfg(not(feature = "debug_build"))]
+fn dump_queue(queue: &tcb_queue_t<SchedLink>) {
+    for tcb in queue.iter().take(DUMP_LIST_LIMIT) {
+        dump_thread(tcb);
+    }
+}
+
+fn dump_thread(tcb: &tcb_t) {
+    let state = tcb.try_get_state();
+    let state: &dyn core::fmt::Debug = match &state {
+        Ok(state) => state,
+        Err(invalid) => invalid,
+    };
+    // a corrupted priority may have no band, the raw length field is logged then
+    let band = time_slice_band(tcb.tcbPriority);
+    let time_slice_length = match tcb.tcbTimeSliceLength {
+        0 if band < TIME_SLICE_BANDS => get_time_slice_band(band),
+        length => length,
+    };
+    info!(
+        "  tcb {:#x} \"{}\" {:?} prio {} mcp {} domain {} cpu {} timeslice {}/{} blocking {:#x}",
+        tcb.get_ptr(),
+        thread_name(tcb),
+        state,
+        tcb.tcbPriority,
+        tcb.tcbMCP,
+        tcb.domain,
+        tcb.tcbAffinity,
+        tcb.tcbTimeSlice,
+        time_slice_length,
+        tcb.tcbState.get_blockingObject()
+    );
+    #[cfg(feature = "kernel_mcs")]
+    dump_sched_context(tcb);
+}
+
+#[cfg(feature = "kernel_mcs")]
+fn dump_sched_context(tcb: &tcb_t) {
+    if tcb.tcbState.get_replyObject() != 0 {
+        info!("    reply object {:#x}", tcb.tcbState.get_replyObject());
+    }
+    let sc: &sched_context_t =
+        match convert_to_option_mut_type_ref::<sched_context_t>(tcb.tcbSchedContext) {
+            Some(sc) => sc,
+            None => return,
+        };
+    dump_refills(sc);
+    let mut reply = sc.scReply;
+    for _ in 0..DUMP_LIST_LIMIT {
+        if reply == 0 {
+            break;
+        }
+        let reply_obj = convert_to_mut_type_ref::<reply_t>(reply);
+        info!("    reply {:#x} caller {:#x}", reply, reply_obj.replyTCB);
+        reply = reply_obj.replyPrev.get_callStackPtr() as usize;
+    }
+}
+
+#[cfg(feature = "kernel_mcs")]
+/// Log a scheduling context and its refills, from head to tail.
+pub(crate) fn dump_refills(sc: &sched_context_t) {
+    if sc.scRefillMax == 0 || sc.scRefillHead >= sc.scRefillMax || sc.scRefillTail >= sc.scRefillMax
+    {
+        info!(
+            "    sc {:#x} period {} with invalid refills: max {} head {} tail {}",
+            sc.get_ptr(),
+            sc.scPeriod,
+            sc.scRefillMax,
+            sc.scRefillHead,
+            sc.scRefillTail
+        );
+        return;
+    }
+    let refills = || {
+        let mut index = Some(sc.scRefillHead);
+        core::iter::from_fn(move || {
+            let current = index?;
+            index = (current != sc.scRefillTail).then(|| sc.refill_next(current));
+            Some(unsafe { &*sc.refill_index(current) })
+        })
+    };
+    info!(
+        "    sc {:#x} budget {} period {} consumed {} core {} refills head {} tail {} max {}",
+        sc.get_ptr(),
+        refills().map(|refill| refill.rAmount).sum::<ticks_t>(),
+        sc.scPeriod,
+        sc.scConsumed,
+        sc.scCore,
+        sc.scRefillHead,
+        sc.scRefillTail,
+        sc.scRefillMax
+    );
+    for refill in refills() {
+        info!(
+            "      refill time {} amount {}",
+            refill.rTime, refill.rAmount
+        );
+    }
+}
@@ -0,0 +1,91 @@
+//! Earliest deadline first scheduling of one priority level of the MCS kernel, enabled by the
+//! `edf_band` feature.
+//!
+//! The ready queues of the priority `ksEdfPriority` are ordered by the absolute deadline of the
+//! scheduling contexts of their threads, i.e. the release time of the head refill plus
+//! `scPeriod`, so `choose_thread` picks the earliest deadline. `sched_append` queues a thread
+//! after those with an equal deadline and `sched_enqueue` in front of them, like they do in a
+//! FIFO queue. A thread of the band only preempts the current thread of the same priority if
+//! its deadline is earlier: `possible_switch_to`, also used by `awaken`, queues it otherwise,
+//! and `schedule` does not switch to it ahead of a queued thread with an earlier deadline.
+
+use sel4_common::platform::time_def::ticks_t;
+use sel4_common::utils::{convert_to_mut_type_ref, convert_to_type_ref};
+
+use crate::sched_context::sched_context_t;
+use crate::tcb_queue::SchedLink;
+use crate::{get_currenct_thread, prio_t, ready_queues_index, tcb_queue_t, tcb_t};
+
+/// The priority scheduled by deadline, `None` if every priority is FIFO.
+pub static mut ksEdfPriority: Option<prio_t> = None;
+
+/// Schedule the threads of `prio` by deadline, or none with `None`.
+///
+/// It is set at boot, while no thread is queued at the old or the new priority.
+pub fn set_edf_priority(prio: Option<prio_t>) {
+    unsafe { ksEdfPriority = prio };
+}
+
+#[inline]
+/// Check if the threads of `prio` are scheduled by deadline
+pub fn is_edf_priority(prio: prio_t) -> bool {
+    unsafe { ksEdfPriority == Some(prio) }
+}
+
+impl tcb_t {
+    #[inline]
+    /// Get the absolute deadline of the TCB, the TCB must have a scheduling context
+    pub fn edf_deadline(&self) -> ticks_t {
+        let sc = convert_to_type_ref::<sched_context_t>(self.tcbSchedContext);
+        unsafe { (*sc.refill_head()).rTime + sc.scPeriod }
+    }
+}
+
+impl tcb_queue_t<SchedLink> {
+    /// Insert a tcb in front of the tcbs with a later deadline, and also in front of those
+    /// with the same deadline if `before_equal`
+    pub fn edf_insert(&mut self, tcb: &mut tcb_t, before_equal: bool) {
+        let deadline = tcb.edf_deadline();
+        let after = self
+            .iter()
+            .find(|queued| {
+                let queued_deadline = queued.edf_deadline();
+                queued_deadline > deadline || (before_equal && queued_deadline == deadline)
+            })
+            .map(|queued| queued.get_ptr());
+        match after {
+            None => self.push_back(tcb),
+            Some(after) => self.insert_before(tcb, convert_to_mut_type_ref::<tcb_t>(after)),
+        }
+    }
+}
+
+/// Check if `target`, about to be switched to, must not preempt the current thread because
+/// they share an EDF priority and its deadline is not earlier
+pub fn edf_defers(target: &tcb_t) -> bool {
+    let current = get_currenct_thread();
+    is_edf_priority(target.tcbPriority)
+        && target.tcbPriority == current.tcbPriority
+        && current.is_schedulable()
+        && target.edf_deadline() >= current.edf_deadline()
+}
+
+/// Check if a thread with an earlier deadline than `candidate` is queued at its EDF priority
+/// on this core
+pub fn edf_queued_before(candidate: &tcb_t, dom: usize) -> bool {
+    if !is_edf_priority(candidate.tcbPriority) {
+        return false;
+    }
+    let index = ready_queues_index(dom, candidate.tcbPriority);
+    let head = unsafe {
+        #[cfg(feature = "enable_smp")]
+        {
+            crate::ksSMP[sel4_common::utils::cpu_id()].ksReadyQueues[index].head
+        }
+        #[cfg(not(feature = "enable_smp"))]
+        {
+            crate::ksReadyQueues[index].head
+        }
+    };
+    head != 0 && convert_to_type_ref::<tcb_t>(head).edf_deadline() < candidate.edf_deadline()
+}
@@ -3,11 +3,14 @@ use sel4_common::structures_gen::{endpoint, notification};
 use crate::tcb_t;
 
 extern "C" {
-    // reorder ep and reorder ntfn is a circular reference problem
-    pub fn reorder_ep(ep: &mut endpoint, thread: &mut tcb_t);
-    pub fn reorder_ntfn(ntfn: &mut notification, thread: &mut tcb_t);
+    // reorder ep and reorder ntfn is a circular reference problem, so the IPC crate provides
+    // them unless it registers `IpcQueueHooks`
+    #[link_name = "reorder_ep"]
+    pub(crate) fn kernel_reorder_ep(ep: &mut endpoint, thread: &mut tcb_t);
+    #[link_name = "reorder_ntfn"]
+    pub(crate) fn kernel_reorder_ntfn(ntfn: &mut notification, thread: &mut tcb_t);
+    #[cfg(feature = "kernel_mcs")]
     pub fn endTimeslice(can_timeout_fault: bool);
+    #[cfg(feature = "kernel_mcs")]
     pub fn handleTimeout(tptr: &mut tcb_t);
-    pub fn migrate_tcb(tcb: &mut tcb_t, new_core: usize);
-    pub fn remote_tcb_stall(tcb: &tcb_t);
 }
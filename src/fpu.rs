@@ -0,0 +1,98 @@
+//! Lazy FPU context switching, enabled by the `have_fpu` feature.
+//!
+//! `ksActiveFPUState` is the TCB whose FPU state is loaded in the FPU of this core, 0 if none.
+//! A context switch only enables the FPU for its owner, so another thread using the FPU traps
+//! into `handle_fpu_fault`, which saves the state of the owner and loads its own.
+//!
+//! Each thread keeps a score of its own restores in `tcbFpuRestores`: a restore adds 2 and a
+//! switch to the thread while another one owns the FPU takes 1 away, so the score of a thread
+//! that uses the FPU every time it runs keeps growing, while that of one that stopped decays.
+//! From `FPU_EAGER_RESTORES` on the thread is eager: its state is loaded on every switch to
+//! it, without the trap. Becoming eager raises the score to twice the threshold, so the thread
+//! is switched eagerly for `FPU_EAGER_RESTORES` switches before it has to trap again to show
+//! it still uses the FPU. `ks_fpu_restore_since_switch` still counts the restores on this
+//! core since a thread was last switched to while it owned the FPU.
+
+use sel4_common::structures::exception_t;
+use sel4_common::utils::convert_to_option_mut_type_ref;
+#[cfg(feature = "enable_smp")]
+use sel4_common::utils::cpu_id;
+
+#[cfg(feature = "enable_smp")]
+use crate::deps::remote_switch_fpu_owner;
+use crate::deps::{disable_fpu, enable_fpu, load_fpu_state, save_fpu_state};
+use crate::{get_currenct_thread, tcb_t, NODE_STATE, NODE_STATE_ON_CORE, SET_NODE_STATE};
+
+/// Restore score from which a thread is switched eagerly.
+pub const FPU_EAGER_RESTORES: usize = 64;
+
+impl tcb_t {
+    #[inline]
+    /// Check if the FPU state of the TCB is loaded on every switch to it
+    pub fn fpu_eager(&self) -> bool {
+        self.tcbFpuRestores >= FPU_EAGER_RESTORES
+    }
+}
+
+/// Make `new_owner` the owner of the FPU of this core, after saving the state of the old owner.
+pub fn switch_local_fpu_owner(new_owner: Option<&mut tcb_t>) {
+    unsafe {
+        enable_fpu();
+        if let Some(owner) = convert_to_option_mut_type_ref::<tcb_t>(NODE_STATE!(ksActiveFPUState))
+        {
+            save_fpu_state(owner);
+        }
+        match new_owner {
+            Some(tcb) => {
+                load_fpu_state(tcb);
+                SET_NODE_STATE!(ksActiveFPUState = tcb.get_ptr());
+            }
+            None => {
+                disable_fpu();
+                SET_NODE_STATE!(ksActiveFPUState = 0);
+            }
+        }
+    }
+}
+
+/// Set up the FPU of this core for a switch to `thread`.
+pub fn fpu_switch_to(thread: &mut tcb_t) {
+    if NODE_STATE!(ksActiveFPUState) == thread.get_ptr() {
+        SET_NODE_STATE!(ks_fpu_restore_since_switch = 0);
+        unsafe { enable_fpu() };
+        return;
+    }
+    thread.tcbFpuRestores = thread.tcbFpuRestores.saturating_sub(1);
+    if thread.fpu_eager() {
+        switch_local_fpu_owner(Some(thread));
+    } else {
+        unsafe { disable_fpu() };
+    }
+}
+
+/// Give the FPU to the current thread, which trapped on using it.
+pub fn handle_fpu_fault() -> exception_t {
+    let thread = get_currenct_thread();
+    assert!(NODE_STATE!(ksActiveFPUState) != thread.get_ptr());
+    switch_local_fpu_owner(Some(thread));
+    SET_NODE_STATE!(ks_fpu_restore_since_switch = NODE_STATE!(ks_fpu_restore_since_switch) + 1);
+    thread.tcbFpuRestores += 2;
+    if thread.fpu_eager() {
+        thread.tcbFpuRestores = 2 * FPU_EAGER_RESTORES;
+    }
+    exception_t::EXCEPTION_NONE
+}
+
+/// Release the FPU if `tcb` owns it on its core, e.g. before it is suspended or migrated.
+pub fn fpu_release(tcb: &mut tcb_t) {
+    tcb.tcbFpuRestores = 0;
+    if NODE_STATE_ON_CORE!(tcb.tcbAffinity, ksActiveFPUState) != tcb.get_ptr() {
+        return;
+    }
+    #[cfg(feature = "enable_smp")]
+    if tcb.tcbAffinity != cpu_id() {
+        unsafe { remote_switch_fpu_owner(0, tcb.tcbAffinity) };
+        return;
+    }
+    switch_local_fpu_owner(None);
+}
@@ -0,0 +1,541 @@
+//! Host-side simulation backend, enabled by the `host_sim` feature.
+//!
+//! It builds the crate for a Linux host so that the scheduler can be exercised by `cargo test`:
+//! - `arch` mocks the registers, the timer and the vspace switch used by the scheduler.
+//! - `Arena` hands out zeroed, correctly aligned `tcb_t`, `sched_context_t`, `reply_t` and
+//!   endpoint objects. The endpoint queues are kept beside the objects and reached through
+//!   the `IpcQueueHooks` that `Sim` registers, or through the `reorder_ep`/`reorder_ntfn`
+//!   stubs once the hooks are dropped.
+//! - `Sim` serialises the tests, because the kernel state is global, and resets it between them.
+//!
+//! Only the single core build is supported.
+
+use core::mem::size_of;
+use std::alloc::{alloc_zeroed, dealloc, Layout};
+use std::sync::{Mutex, MutexGuard};
+use std::vec::Vec;
+
+use sel4_common::sel4_config::{
+    CONFIG_MAX_NUM_NODES, CONFIG_NUM_DOMAINS, CONFIG_TIME_SLICE, L2_BITMAP_SIZE, NUM_READY_QUEUES,
+    SEL4_TCB_BITS, TCB_OFFSET,
+};
+#[cfg(any(feature = "kernel_mcs", feature = "ep_priority_queue"))]
+use sel4_common::structures_gen::endpoint;
+use sel4_common::utils::convert_to_mut_type_ref;
+use sel4_common::BIT;
+
+#[cfg(feature = "kernel_mcs")]
+use crate::reply::reply_t;
+#[cfg(feature = "kernel_mcs")]
+use crate::sched_context::{refill, sched_context_t};
+use crate::{
+    create_idle_thread, dschedule_t, get_idle_thread, ready_queues_index, set_current_thread,
+    tcb_queue_t, tcb_t, SchedLink, KS_DOM_SCHEDULE_MAX_LENGTH, SCHEDULER_ACTION_CHOOSE_NEW_THREAD,
+    TIME_SLICE_BANDS,
+};
+#[cfg(any(feature = "kernel_mcs", feature = "ep_priority_queue"))]
+use crate::{EPLink, ThreadState};
+
+/// Simulated replacement for `sel4_common::arch`, `sel4_common::platform` and `sel4_vspace`.
+pub mod arch {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    use sel4_common::platform::time_def::{ticks_t, time_t};
+    use sel4_common::structures_gen::{cap, lookup_fault};
+
+    pub const MSG_REGISTER_NUM: usize = 4;
+    pub const N_SYSCALL_MESSAGE: usize = 10;
+    pub const N_EXCEPTON_MESSAGE: usize = 2;
+    const N_REGISTERS: usize = 32;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    /// The registers the crate reads and writes.
+    pub enum ArchReg {
+        FAULT_IP,
+        NEXT_IP,
+        MsgInfo,
+        Msg(usize),
+        FaultMessage(usize, usize),
+    }
+
+    impl ArchReg {
+        fn index(self) -> usize {
+            match self {
+                ArchReg::FAULT_IP => 0,
+                ArchReg::NEXT_IP => 1,
+                ArchReg::MsgInfo => 2,
+                ArchReg::Msg(i) => 3 + i,
+                ArchReg::FaultMessage(_, i) => 3 + MSG_REGISTER_NUM + i,
+            }
+        }
+    }
+
+    #[repr(C)]
+    #[derive(Debug, Clone)]
+    /// A flat register file standing in for the architecture TCB.
+    pub struct ArchTCB {
+        pub registers: [usize; N_REGISTERS],
+    }
+
+    impl Default for ArchTCB {
+        fn default() -> Self {
+            ArchTCB {
+                registers: [0; N_REGISTERS],
+            }
+        }
+    }
+
+    impl ArchTCB {
+        #[inline]
+        pub fn get_register(&self, reg: ArchReg) -> usize {
+            self.registers[reg.index()]
+        }
+        #[inline]
+        pub fn set_register(&mut self, reg: ArchReg, w: usize) {
+            self.registers[reg.index()] = w;
+        }
+        pub fn config_idle_thread(&mut self, idle_thread: usize, _core: usize) {
+            self.set_register(ArchReg::NEXT_IP, idle_thread);
+        }
+    }
+
+    #[repr(u64)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum vm_rights_t {
+        VMKernelOnly = 1,
+        VMReadOnly = 2,
+        VMReadWrite = 3,
+    }
+
+    // One simulated tick is one microsecond.
+    pub fn us_to_ticks(us: time_t) -> ticks_t {
+        us
+    }
+    pub fn ticks_to_us(ticks: ticks_t) -> time_t {
+        ticks
+    }
+    pub fn get_kernel_wcet_us() -> time_t {
+        10
+    }
+    pub fn get_kernel_wcet_ticks() -> ticks_t {
+        us_to_ticks(get_kernel_wcet_us())
+    }
+    pub fn get_max_ticks_to_us() -> ticks_t {
+        ticks_t::MAX
+    }
+    pub fn get_max_us_to_ticks() -> time_t {
+        time_t::MAX
+    }
+    pub fn get_timer_precision() -> ticks_t {
+        0
+    }
+
+    static SIM_TIME: AtomicUsize = AtomicUsize::new(0);
+    static SIM_DEADLINE: AtomicUsize = AtomicUsize::new(usize::MAX);
+    static SIM_VM_ROOT_SWITCHES: AtomicUsize = AtomicUsize::new(0);
+
+    /// The simulated platform timer.
+    pub struct SimTimer;
+
+    pub static timer: SimTimer = SimTimer;
+
+    pub trait Timer_func {
+        fn get_current_time(&self) -> ticks_t;
+        fn set_deadline(&self, deadline: ticks_t);
+    }
+
+    impl Timer_func for SimTimer {
+        fn get_current_time(&self) -> ticks_t {
+            SIM_TIME.load(Ordering::SeqCst) as ticks_t
+        }
+        fn set_deadline(&self, deadline: ticks_t) {
+            SIM_DEADLINE.store(deadline as usize, Ordering::SeqCst);
+        }
+    }
+
+    impl SimTimer {
+        /// Move the simulated clock to `now`.
+        pub fn set_time(&self, now: ticks_t) {
+            SIM_TIME.store(now as usize, Ordering::SeqCst);
+        }
+        /// The last deadline programmed through `set_deadline`.
+        pub fn deadline(&self) -> ticks_t {
+            SIM_DEADLINE.load(Ordering::SeqCst) as ticks_t
+        }
+    }
+
+    /// Accept every vspace, and only count the switches.
+    pub fn set_vm_root(_vspace: &cap) -> Result<(), lookup_fault> {
+        SIM_VM_ROOT_SWITCHES.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Number of `set_vm_root` calls since the last reset.
+    pub fn vm_root_switches() -> usize {
+        SIM_VM_ROOT_SWITCHES.load(Ordering::SeqCst)
+    }
+
+    pub(super) fn reset() {
+        SIM_TIME.store(0, Ordering::SeqCst);
+        SIM_DEADLINE.store(usize::MAX, Ordering::SeqCst);
+        SIM_VM_ROOT_SWITCHES.store(0, Ordering::SeqCst);
+    }
+}
+
+/// The kernel functions this crate imports from its users, implemented for the simulation.
+#[cfg(feature = "kernel_mcs")]
+mod kernel_stubs {
+    use crate::tcb_t;
+    use crate::{get_currenct_thread, get_current_sc};
+
+    #[no_mangle]
+    pub extern "C" fn endTimeslice(_can_timeout_fault: bool) {
+        let sc = get_current_sc();
+        if sc.refill_ready() && sc.refill_sufficient(0) {
+            get_currenct_thread().sched_append();
+        } else {
+            sc.postpone();
+        }
+    }
+
+    #[no_mangle]
+    pub extern "C" fn handleTimeout(_tptr: &mut tcb_t) {}
+}
+
+/// The FPU control functions this crate imports from the architecture code, simulated.
+#[cfg(feature = "have_fpu")]
+pub mod fpu {
+    use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    use crate::tcb_t;
+
+    static FPU_ENABLED: AtomicBool = AtomicBool::new(false);
+    static FPU_LOADS: AtomicUsize = AtomicUsize::new(0);
+    static FPU_SAVES: AtomicUsize = AtomicUsize::new(0);
+
+    #[no_mangle]
+    pub extern "C" fn enable_fpu() {
+        FPU_ENABLED.store(true, Ordering::SeqCst);
+    }
+
+    #[no_mangle]
+    pub extern "C" fn disable_fpu() {
+        FPU_ENABLED.store(false, Ordering::SeqCst);
+    }
+
+    #[no_mangle]
+    pub extern "C" fn save_fpu_state(_tcb: &mut tcb_t) {
+        FPU_SAVES.fetch_add(1, Ordering::SeqCst);
+    }
+
+    #[no_mangle]
+    pub extern "C" fn load_fpu_state(_tcb: &tcb_t) {
+        FPU_LOADS.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Check if the simulated FPU is enabled.
+    pub fn fpu_enabled() -> bool {
+        FPU_ENABLED.load(Ordering::SeqCst)
+    }
+
+    /// Get the number of FPU states loaded and saved since the simulation was reset.
+    pub fn fpu_loads_saves() -> (usize, usize) {
+        (
+            FPU_LOADS.load(Ordering::SeqCst),
+            FPU_SAVES.load(Ordering::SeqCst),
+        )
+    }
+
+    pub(super) fn reset() {
+        FPU_ENABLED.store(false, Ordering::SeqCst);
+        FPU_LOADS.store(0, Ordering::SeqCst);
+        FPU_SAVES.store(0, Ordering::SeqCst);
+    }
+}
+
+/// The queues of the simulated endpoints and notifications, kept beside the objects by address.
+#[cfg(any(feature = "kernel_mcs", feature = "ep_priority_queue"))]
+mod ipc_queues {
+    use std::sync::Mutex;
+    use std::vec::Vec;
+
+    use sel4_common::structures_gen::{endpoint, notification};
+
+    use crate::{tcb_queue_t, tcb_t, EPLink, IpcQueueHooks};
+
+    static QUEUES: Mutex<Vec<(usize, tcb_queue_t<EPLink>)>> = Mutex::new(Vec::new());
+
+    pub fn get(obj: usize) -> tcb_queue_t<EPLink> {
+        let queues = QUEUES.lock().unwrap();
+        queues
+            .iter()
+            .find(|(addr, _)| *addr == obj)
+            .map_or(tcb_queue_t::new(), |(_, queue)| *queue)
+    }
+
+    pub fn set(obj: usize, queue: tcb_queue_t<EPLink>) {
+        let mut queues = QUEUES.lock().unwrap();
+        queues.retain(|(addr, _)| *addr != obj);
+        queues.push((obj, queue));
+    }
+
+    pub fn reset() {
+        QUEUES.lock().unwrap().clear();
+    }
+
+    /// Move `thread` to the position of its priority in the queue of `obj`, like the IPC
+    /// crate does when no hooks are registered.
+    fn reorder(obj: usize, thread: &mut tcb_t) {
+        let mut queue = get(obj);
+        queue.ep_dequeue(thread);
+        queue.ep_append(thread);
+        set(obj, queue);
+    }
+
+    #[no_mangle]
+    pub extern "C" fn reorder_ep(ep: &mut endpoint, thread: &mut tcb_t) {
+        reorder(ep as *mut _ as usize, thread);
+    }
+
+    #[no_mangle]
+    pub extern "C" fn reorder_ntfn(ntfn: &mut notification, thread: &mut tcb_t) {
+        reorder(ntfn as *mut _ as usize, thread);
+    }
+
+    pub struct SimIpcQueues;
+
+    impl IpcQueueHooks for SimIpcQueues {
+        fn ep_queue(&self, ep: &endpoint) -> tcb_queue_t<EPLink> {
+            get(ep as *const _ as usize)
+        }
+        fn set_ep_queue(&self, ep: &mut endpoint, queue: tcb_queue_t<EPLink>) {
+            set(ep as *mut _ as usize, queue)
+        }
+        fn ntfn_queue(&self, ntfn: &notification) -> tcb_queue_t<EPLink> {
+            get(ntfn as *const _ as usize)
+        }
+        fn set_ntfn_queue(&self, ntfn: &mut notification, queue: tcb_queue_t<EPLink>) {
+            set(ntfn as *mut _ as usize, queue)
+        }
+    }
+
+    pub static SIM_IPC_QUEUES: SimIpcQueues = SimIpcQueues;
+}
+
+/// An allocator for kernel objects, which frees them all when dropped.
+pub struct Arena {
+    blocks: Vec<(*mut u8, Layout)>,
+}
+
+impl Arena {
+    pub fn new() -> Self {
+        Arena { blocks: Vec::new() }
+    }
+
+    /// Allocate a zeroed block of `size` bytes aligned to `align`.
+    pub fn alloc(&mut self, size: usize, align: usize) -> usize {
+        let layout = Layout::from_size_align(size, align).unwrap();
+        let ptr = unsafe { alloc_zeroed(layout) };
+        assert!(!ptr.is_null());
+        self.blocks.push((ptr, layout));
+        ptr as usize
+    }
+
+    /// Allocate a TCB object, laid out like the kernel does with the cte slots in front of it.
+    pub fn new_tcb(&mut self) -> &'static mut tcb_t {
+        let base = self.alloc(BIT!(SEL4_TCB_BITS), BIT!(SEL4_TCB_BITS));
+        convert_to_mut_type_ref::<tcb_t>(base + TCB_OFFSET)
+    }
+
+    #[cfg(feature = "kernel_mcs")]
+    /// Allocate a scheduling context object with room for `max_refills` refills.
+    pub fn new_sched_context(&mut self, max_refills: usize) -> &'static mut sched_context_t {
+        let size =
+            (size_of::<sched_context_t>() + max_refills * size_of::<refill>()).next_power_of_two();
+        convert_to_mut_type_ref::<sched_context_t>(self.alloc(size, size))
+    }
+
+    #[cfg(feature = "kernel_mcs")]
+    pub fn new_reply(&mut self) -> &'static mut reply_t {
+        let size = size_of::<reply_t>().next_power_of_two();
+        convert_to_mut_type_ref::<reply_t>(self.alloc(size, size))
+    }
+
+    #[cfg(any(feature = "kernel_mcs", feature = "ep_priority_queue"))]
+    pub fn new_endpoint(&mut self) -> &'static mut endpoint {
+        let size = size_of::<endpoint>().next_power_of_two();
+        convert_to_mut_type_ref::<endpoint>(self.alloc(size, size))
+    }
+}
+
+impl Default for Arena {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for Arena {
+    fn drop(&mut self) {
+        for (ptr, layout) in self.blocks.drain(..) {
+            unsafe { dealloc(ptr, layout) };
+        }
+    }
+}
+
+/// Length of a scheduler tick in simulated timer ticks, i.e. 1 ms.
+pub const SIM_TICK_LENGTH: usize = 1000;
+
+static SIM_LOCK: Mutex<()> = Mutex::new(());
+
+/// A freshly booted simulated kernel, with only the idle thread running.
+///
+/// The kernel state is global, so a `Sim` holds a lock that serialises the tests using it.
+pub struct Sim {
+    pub arena: Arena,
+    _guard: MutexGuard<'static, ()>,
+}
+
+impl Sim {
+    pub fn new() -> Self {
+        let guard = SIM_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        reset_kernel_state();
+        create_idle_thread();
+        set_current_thread(get_idle_thread());
+        #[cfg(feature = "kernel_mcs")]
+        crate::SET_NODE_STATE!(ksCurSC = crate::NODE_STATE!(ksIdleSC));
+        #[cfg(feature = "tickless")]
+        crate::tickless_init(SIM_TICK_LENGTH as _);
+        #[cfg(any(feature = "kernel_mcs", feature = "ep_priority_queue"))]
+        crate::set_ipc_queue_hooks(&ipc_queues::SIM_IPC_QUEUES);
+        Sim {
+            arena: Arena::new(),
+            _guard: guard,
+        }
+    }
+
+    /// Create an inactive thread with the given priority and domain.
+    pub fn new_thread(&mut self, prio: usize, dom: usize) -> &'static mut tcb_t {
+        let tcb = self.arena.new_tcb();
+        tcb.tcbPriority = prio;
+        tcb.tcbMCP = prio;
+        tcb.domain = dom;
+        tcb.tcbTimeSlice = tcb.get_time_slice_length();
+        #[cfg(feature = "debug_build")]
+        tcb.debug_append();
+        tcb
+    }
+
+    #[cfg(any(feature = "kernel_mcs", feature = "ep_priority_queue"))]
+    /// Block a runnable thread on `ep` in `state`, queued by priority like the IPC crate does.
+    pub fn block_on_endpoint(&mut self, tcb: &mut tcb_t, ep: &mut endpoint, state: ThreadState) {
+        crate::set_thread_state(tcb, state);
+        tcb.tcbState.set_blockingObject(ep as *mut _ as u64);
+        let mut queue = endpoint_queue(ep);
+        queue.ep_append(tcb);
+        ipc_queues::set(ep as *mut _ as usize, queue);
+    }
+
+    #[cfg(any(feature = "kernel_mcs", feature = "ep_priority_queue"))]
+    /// Drop the registered `IpcQueueHooks`, so that queues are reordered through the
+    /// `reorder_ep`/`reorder_ntfn` functions of the IPC crate instead.
+    pub fn drop_ipc_queue_hooks(&mut self) {
+        unsafe { crate::ipc_queue::ksIpcQueueHooks = None };
+    }
+
+    /// Move the simulated clock to `now`.
+    pub fn set_time(&self, now: usize) {
+        arch::timer.set_time(now as _);
+    }
+
+    /// Move the simulated clock forward by one tick, and take the timer interrupt.
+    pub fn tick(&self) {
+        use arch::Timer_func;
+        self.set_time(arch::timer.get_current_time() as usize + SIM_TICK_LENGTH);
+        crate::timer_tick();
+    }
+}
+
+/// Get a copy of the ready queue of the given domain and priority.
+pub fn ready_queue(dom: usize, prio: usize) -> tcb_queue_t<SchedLink> {
+    unsafe { crate::ksReadyQueues[ready_queues_index(dom, prio)] }
+}
+
+#[cfg(any(feature = "kernel_mcs", feature = "ep_priority_queue"))]
+/// Get a copy of the queue of a simulated endpoint.
+pub fn endpoint_queue(ep: &endpoint) -> tcb_queue_t<EPLink> {
+    ipc_queues::get(ep as *const _ as usize)
+}
+
+fn reset_kernel_state() {
+    arch::reset();
+    #[cfg(feature = "have_fpu")]
+    fpu::reset();
+    #[cfg(any(feature = "kernel_mcs", feature = "ep_priority_queue"))]
+    ipc_queues::reset();
+    unsafe {
+        crate::ksReadyQueues = [tcb_queue_t::new(); NUM_READY_QUEUES];
+        crate::ksReadyQueuesL1Bitmap = [0; CONFIG_NUM_DOMAINS];
+        crate::ksReadyQueuesL2Bitmap = [[0; L2_BITMAP_SIZE]; CONFIG_NUM_DOMAINS];
+        crate::ksCurThread = 0;
+        crate::ksIdleThread = 0;
+        crate::ksSchedulerAction = SCHEDULER_ACTION_CHOOSE_NEW_THREAD;
+        crate::ksDomainTime = 0;
+        crate::ksCurDomain = 0;
+        crate::ksDomScheduleIdx = 0;
+        crate::ksWorkUnitsCompleted = 0;
+        crate::ksDomSchedule = [dschedule_t {
+            domain: 0,
+            length: 0,
+        }; KS_DOM_SCHEDULE_MAX_LENGTH];
+        crate::ksDomSchedule[0].length = 60;
+        crate::ksDomScheduleLength = 1;
+        crate::ksTimeSliceBands = [CONFIG_TIME_SLICE; TIME_SLICE_BANDS];
+        #[cfg(feature = "edf_band")]
+        {
+            crate::ksEdfPriority = None;
+        }
+        #[cfg(feature = "sc_admission")]
+        {
+            crate::ksSCUtilisation = [[0; CONFIG_NUM_DOMAINS]; CONFIG_MAX_NUM_NODES];
+            crate::ksDomainUtilisationCap = [crate::UTILISATION_FULL; CONFIG_NUM_DOMAINS];
+        }
+        crate::deps::ksIdleThreadTCB.data = [[0; BIT!(SEL4_TCB_BITS)]; CONFIG_MAX_NUM_NODES];
+        #[cfg(feature = "benchmark_track_utilisation")]
+        {
+            crate::ksBenchmarkTotalTicks = 0;
+        }
+        #[cfg(feature = "tickless")]
+        {
+            crate::ksTickStart = 0;
+            crate::ksTickLength = 0;
+        }
+        #[cfg(feature = "smp_load_balance")]
+        {
+            crate::ksReadyThreads = [0; CONFIG_NUM_DOMAINS];
+            crate::load_balance::ksLoadBalanceTicks = 0;
+        }
+        #[cfg(feature = "sched_trace")]
+        crate::sched_trace_clear(0);
+        #[cfg(feature = "debug_build")]
+        {
+            crate::ksDebugTCBs = tcb_queue_t::new();
+        }
+        #[cfg(feature = "have_fpu")]
+        {
+            crate::ksActiveFPUState = 0;
+            crate::ks_fpu_restore_since_switch = 0;
+        }
+        #[cfg(feature = "kernel_mcs")]
+        {
+            use sel4_common::sel4_config::SEL4_MIN_SCHED_CONTEXT_BITS;
+            crate::ksReleaseQueue = tcb_queue_t::new();
+            crate::ksCurSC = 0;
+            crate::ksConsumed = 0;
+            crate::ksCurTime = 0;
+            crate::ksReprogram = false;
+            crate::ksIdleSC = 0;
+            crate::deps::ksIdleThreadSC.data =
+                [[0; BIT!(SEL4_MIN_SCHED_CONTEXT_BITS)]; CONFIG_MAX_NUM_NODES];
+        }
+    }
+}
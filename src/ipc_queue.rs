@@ -0,0 +1,56 @@
+//! Reordering of endpoint and notification queues after a priority change.
+//!
+//! The queue of an endpoint or notification is stored in the object itself, whose accessors
+//! belong to the IPC crate. To avoid a dependency cycle that crate registers an
+//! `IpcQueueHooks` implementation with `set_ipc_queue_hooks` at boot, and the reordering is
+//! done here with `tcb_queue_t::ep_dequeue`/`ep_append`. Without hooks the reordering falls
+//! back to the `reorder_ep`/`reorder_ntfn` functions exported by that crate, as before.
+
+use sel4_common::structures_gen::{endpoint, notification};
+
+use crate::ffi::{kernel_reorder_ep, kernel_reorder_ntfn};
+use crate::{tcb_queue_t, tcb_t, EPLink};
+
+/// Read and write the TCB queue stored in an endpoint or notification.
+pub trait IpcQueueHooks {
+    fn ep_queue(&self, ep: &endpoint) -> tcb_queue_t<EPLink>;
+    fn set_ep_queue(&self, ep: &mut endpoint, queue: tcb_queue_t<EPLink>);
+    fn ntfn_queue(&self, ntfn: &notification) -> tcb_queue_t<EPLink>;
+    fn set_ntfn_queue(&self, ntfn: &mut notification, queue: tcb_queue_t<EPLink>);
+}
+
+pub(crate) static mut ksIpcQueueHooks: Option<&'static dyn IpcQueueHooks> = None;
+
+/// Register the hooks used to reach the queues of endpoints and notifications.
+pub fn set_ipc_queue_hooks(hooks: &'static dyn IpcQueueHooks) {
+    unsafe { ksIpcQueueHooks = Some(hooks) }
+}
+
+#[inline]
+fn ipc_queue_hooks() -> Option<&'static dyn IpcQueueHooks> {
+    unsafe { ksIpcQueueHooks }
+}
+
+/// Move `thread` to the position of its current priority in the queue of `ep`
+pub fn reorder_ep(ep: &mut endpoint, thread: &mut tcb_t) {
+    let hooks = match ipc_queue_hooks() {
+        Some(hooks) => hooks,
+        None => return unsafe { kernel_reorder_ep(ep, thread) },
+    };
+    let mut queue = hooks.ep_queue(ep);
+    queue.ep_dequeue(thread);
+    queue.ep_append(thread);
+    hooks.set_ep_queue(ep, queue);
+}
+
+/// Move `thread` to the position of its current priority in the queue of `ntfn`
+pub fn reorder_ntfn(ntfn: &mut notification, thread: &mut tcb_t) {
+    let hooks = match ipc_queue_hooks() {
+        Some(hooks) => hooks,
+        None => return unsafe { kernel_reorder_ntfn(ntfn, thread) },
+    };
+    let mut queue = hooks.ntfn_queue(ntfn);
+    queue.ep_dequeue(thread);
+    queue.ep_append(thread);
+    hooks.set_ntfn_queue(ntfn, queue);
+}
@@ -3,28 +3,93 @@
 //!  See more details in ../doc.md
 
 #![feature(core_intrinsics)]
-#![no_std]
+#![cfg_attr(not(feature = "host_sim"), no_std)]
 #![allow(internal_features)]
 #![allow(non_snake_case)]
 #![allow(non_camel_case_types)]
 #![allow(non_upper_case_globals)]
 
+#[cfg(all(feature = "host_sim", feature = "enable_smp"))]
+compile_error!("the host_sim backend only supports the single core build");
+
+#[cfg(all(feature = "benchmark_track_utilisation", feature = "kernel_mcs"))]
+compile_error!("utilisation tracking is for the non-MCS kernel, MCS accounts time in scConsumed");
+
+#[cfg(all(feature = "smp_load_balance", feature = "kernel_mcs"))]
+compile_error!(
+    "load balancing is for the non-MCS kernel, MCS threads follow their scheduling contexts"
+);
+
+#[cfg(all(feature = "tickless", feature = "kernel_mcs"))]
+compile_error!(
+    "tickless scheduling is for the non-MCS kernel, MCS already programs one-shot deadlines"
+);
+
+#[cfg(feature = "sc_admission")]
+mod admission;
+mod arch;
+#[cfg(feature = "benchmark_track_utilisation")]
+mod benchmark;
+#[cfg(feature = "debug_build")]
+mod debug;
 mod deps;
-#[cfg(feature = "kernel_mcs")]
+mod dump;
+#[cfg(feature = "edf_band")]
+mod edf;
+#[cfg(any(feature = "kernel_mcs", feature = "ep_priority_queue"))]
 mod ffi;
+#[cfg(feature = "have_fpu")]
+mod fpu;
+#[cfg(feature = "host_sim")]
+pub mod host_sim;
+#[cfg(any(feature = "kernel_mcs", feature = "ep_priority_queue"))]
+mod ipc_queue;
+#[cfg(feature = "smp_load_balance")]
+mod load_balance;
+#[cfg(feature = "priority_inheritance")]
+mod priority_inheritance;
+#[cfg(feature = "release_heap")]
+mod release_heap;
 #[cfg(feature = "kernel_mcs")]
 pub mod sched_context;
+#[cfg(feature = "sched_trace")]
+mod sched_trace;
 mod scheduler;
 mod structures;
 pub mod tcb;
 mod tcb_queue;
 mod thread_state;
-#[cfg(feature = "kernel_mcs")]
+#[cfg(feature = "tickless")]
+mod tickless;
+#[cfg(any(feature = "kernel_mcs", feature = "ep_priority_queue"))]
 pub use ffi::*;
 #[cfg(feature = "kernel_mcs")]
 pub mod reply;
+#[cfg(feature = "sc_admission")]
+pub use admission::*;
+#[cfg(feature = "benchmark_track_utilisation")]
+pub use benchmark::*;
+#[cfg(feature = "debug_build")]
+pub use debug::*;
+pub use dump::*;
+#[cfg(feature = "edf_band")]
+pub use edf::*;
+#[cfg(feature = "have_fpu")]
+pub use fpu::*;
+#[cfg(any(feature = "kernel_mcs", feature = "ep_priority_queue"))]
+pub use ipc_queue::*;
+#[cfg(feature = "smp_load_balance")]
+pub use load_balance::*;
+#[cfg(feature = "priority_inheritance")]
+pub use priority_inheritance::*;
+#[cfg(feature = "release_heap")]
+pub use release_heap::*;
+#[cfg(feature = "sched_trace")]
+pub use sched_trace::*;
 pub use scheduler::*;
 pub use structures::*;
 pub use tcb::*;
 pub use tcb_queue::*;
 pub use thread_state::*;
+#[cfg(feature = "tickless")]
+pub use tickless::*;
@@ -0,0 +1,148 @@
+//! Load balancing of runnable threads across cores, enabled by the `smp_load_balance` feature.
+//!
+//! The load of a core is the number of threads of the current domain it could run: those in
+//! its ready queues plus its current thread unless that is the idle thread. The queued threads
+//! are counted per domain in `ksReadyThreads` by `sched_enqueue`/`sched_dequeue`, so reading a
+//! load does not walk the queues. Core 0 evens out the loads every `LOAD_BALANCE_PERIOD`
+//! ticks, and a core about to go idle pulls a thread from the busiest core. Threads are moved
+//! with `sched_dequeue`/`sched_enqueue`, so the target core is kicked through
+//! `ipiReschedulePending` as for any remote enqueue.
+//! Pinned threads (`tcbPinned`) are never moved; `set_affinity` pins the thread it moves.
+//!
+//! In the single core build the counts are kept and the balancer runs, but never finds another
+//! core to move a thread to.
+
+use sel4_common::sel4_config::{CONFIG_MAX_NUM_NODES, CONFIG_NUM_PRIORITIES};
+use sel4_common::utils::{convert_to_mut_type_ref, cpu_id};
+
+use crate::{
+    ksCurDomain, ready_queues_index, tcb_queue_t, tcb_t, SchedLink, NODE_STATE_ON_CORE,
+    SET_NODE_STATE_ON_CORE,
+};
+
+/// Number of timer ticks between two balancing passes.
+pub const LOAD_BALANCE_PERIOD: usize = 10;
+
+pub(crate) static mut ksLoadBalanceTicks: usize = 0;
+
+impl tcb_t {
+    #[inline]
+    /// Keep the TCB on its core when the load is balanced, or let it be moved again
+    pub fn set_pinned(&mut self, pinned: bool) {
+        self.tcbPinned = pinned;
+    }
+}
+
+/// Count a thread of domain `dom` entering or leaving the ready queues of `cpu`.
+pub(crate) fn count_ready(_cpu: usize, dom: usize, queued: bool) {
+    let mut counts = NODE_STATE_ON_CORE!(_cpu, ksReadyThreads);
+    if queued {
+        counts[dom] += 1;
+    } else {
+        counts[dom] -= 1;
+    }
+    SET_NODE_STATE_ON_CORE!(_cpu, ksReadyThreads = counts);
+}
+
+/// Get the number of threads of domain `dom` in the ready queues of `cpu`.
+pub fn ready_threads(_cpu: usize, dom: usize) -> usize {
+    NODE_STATE_ON_CORE!(_cpu, ksReadyThreads)[dom]
+}
+
+/// Get the number of threads of the current domain that `cpu` could run.
+pub fn core_load(cpu: usize) -> usize {
+    let busy = NODE_STATE_ON_CORE!(cpu, ksCurThread) != NODE_STATE_ON_CORE!(cpu, ksIdleThread);
+    busy as usize + ready_threads(cpu, unsafe { ksCurDomain })
+}
+
+fn ready_queue_on_core(_cpu: usize, index: usize) -> tcb_queue_t<SchedLink> {
+    unsafe {
+        #[cfg(feature = "enable_smp")]
+        {
+            crate::ksSMP[_cpu].ksReadyQueues[index]
+        }
+        #[cfg(not(feature = "enable_smp"))]
+        {
+            crate::ksReadyQueues[index]
+        }
+    }
+}
+
+/// Find the highest priority thread of the current domain queued on `cpu` that may move.
+fn find_migratable(cpu: usize) -> Option<&'static mut tcb_t> {
+    let dom = unsafe { ksCurDomain };
+    if ready_threads(cpu, dom) == 0 {
+        return None;
+    }
+    (0..CONFIG_NUM_PRIORITIES).rev().find_map(|prio| {
+        ready_queue_on_core(cpu, ready_queues_index(dom, prio))
+            .iter()
+            .find(|tcb| !tcb.tcbPinned)
+            .map(|tcb| convert_to_mut_type_ref::<tcb_t>(tcb.get_ptr()))
+    })
+}
+
+/// Move a queued thread to the ready queues of `cpu`.
+fn migrate_queued(tcb: &mut tcb_t, cpu: usize) {
+    assert!(tcb.tcbState.get_tcbQueued() != 0);
+    tcb.sched_dequeue();
+    tcb.migrate(cpu);
+    tcb.sched_enqueue();
+}
+
+/// Move threads from the busiest to the least loaded cores until the loads differ by at
+/// most one, or nothing else can be moved.
+pub fn load_balance() {
+    // every move narrows the gap, so this bounds the work of one pass
+    for _ in 0..CONFIG_MAX_NUM_NODES {
+        let loads: [usize; CONFIG_MAX_NUM_NODES] = core::array::from_fn(core_load);
+        let busiest = (0..CONFIG_MAX_NUM_NODES)
+            .max_by_key(|&cpu| loads[cpu])
+            .unwrap();
+        let idlest = (0..CONFIG_MAX_NUM_NODES)
+            .min_by_key(|&cpu| loads[cpu])
+            .unwrap();
+        if loads[busiest] < loads[idlest] + 2 {
+            return;
+        }
+        match find_migratable(busiest) {
+            Some(tcb) => migrate_queued(tcb, idlest),
+            None => return,
+        }
+    }
+}
+
+/// Count a timer tick on the boot core, and balance the loads once per period.
+pub fn load_balance_tick() {
+    if cpu_id() != 0 {
+        return;
+    }
+    unsafe {
+        ksLoadBalanceTicks += 1;
+        if ksLoadBalanceTicks < LOAD_BALANCE_PERIOD {
+            return;
+        }
+        ksLoadBalanceTicks = 0;
+    }
+    load_balance();
+}
+
+/// Pull a thread from the busiest core to `cpu`, which has nothing to run.
+///
+/// Returns whether a thread was moved.
+pub fn pull_work(cpu: usize) -> bool {
+    let busiest = (0..CONFIG_MAX_NUM_NODES)
+        .filter(|&other| other != cpu)
+        .max_by_key(|&other| core_load(other));
+    match busiest {
+        // the busiest core keeps running its current thread, so it needs a queued spare
+        Some(busiest) if core_load(busiest) >= 2 => match find_migratable(busiest) {
+            Some(tcb) => {
+                migrate_queued(tcb, cpu);
+                true
+            }
+            None => false,
+        },
+        _ => false,
+    }
+}
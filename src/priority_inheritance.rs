@@ -0,0 +1,137 @@
+//! Priority inheritance along call/reply chains, enabled by the `priority_inheritance` feature.
+//!
+//! A client that is `BlockedOnReply` is linked into the `tcbPIClients` list of the server
+//! that holds its reply cap. The server then runs at the highest priority of itself and its
+//! clients: `tcbPriority` is this effective priority, which is what the ready and endpoint
+//! queues use, and `tcbBasePriority` keeps the server's own priority while it has clients.
+//! A server that is itself blocked on reply passes the priority on along the chain.
+
+use sel4_common::utils::convert_to_mut_type_ref;
+
+use crate::tcb_queue::{link_from_ptr, link_to_ptr, tcb_link_t, TcbLink};
+use crate::{possible_switch_to, prio_t, reschedule_required, tcb_t};
+
+/// The `tcbPINext`/`tcbPIPrev` links, used by the `tcbPIClients` list of a server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct PILink;
+
+impl TcbLink for PILink {
+    #[inline]
+    fn next(tcb: &tcb_t) -> tcb_link_t {
+        link_from_ptr(tcb.tcbPINext)
+    }
+    #[inline]
+    fn prev(tcb: &tcb_t) -> tcb_link_t {
+        link_from_ptr(tcb.tcbPIPrev)
+    }
+    #[inline]
+    fn set_next(tcb: &mut tcb_t, next: tcb_link_t) {
+        tcb.tcbPINext = link_to_ptr(next);
+    }
+    #[inline]
+    fn set_prev(tcb: &mut tcb_t, prev: tcb_link_t) {
+        tcb.tcbPIPrev = link_to_ptr(prev);
+    }
+}
+
+impl tcb_t {
+    #[inline]
+    /// Get the priority of the TCB without the priorities it inherits
+    pub fn base_priority(&self) -> prio_t {
+        if self.tcbPIClients.empty() {
+            self.tcbPriority
+        } else {
+            self.tcbBasePriority
+        }
+    }
+
+    /// Record that `self` is blocked on a reply from `server`, and boost the server chain
+    pub fn pi_block_on(&mut self, server: &mut tcb_t) {
+        assert!(self.tcbPIServer == 0);
+        assert!(self.get_ptr() != server.get_ptr());
+        if server.tcbPIClients.empty() {
+            server.tcbBasePriority = server.tcbPriority;
+        }
+        server.tcbPIClients.push_back(self);
+        self.tcbPIServer = server.get_ptr();
+        server.pi_update();
+    }
+
+    /// Detach `self` from the server it waits on, and let the server chain drop its boost
+    pub fn pi_unblock(&mut self) {
+        if self.tcbPIServer == 0 {
+            return;
+        }
+        let server = convert_to_mut_type_ref::<tcb_t>(self.tcbPIServer);
+        server.tcbPIClients.unlink(self);
+        self.tcbPIServer = 0;
+        server.pi_update();
+    }
+
+    /// Detach all clients, e.g. when `self` is suspended, and drop back to the base priority
+    pub fn pi_release_clients(&mut self) {
+        if self.tcbPIClients.empty() {
+            return;
+        }
+        while let Some(client) = self.tcbPIClients.pop_front() {
+            client.tcbPIServer = 0;
+        }
+        self.pi_update();
+    }
+
+    #[inline]
+    /// Pass a priority change of `self` on to the server it is blocked on
+    pub(crate) fn pi_propagate(&mut self) {
+        if self.tcbPIServer != 0 {
+            convert_to_mut_type_ref::<tcb_t>(self.tcbPIServer).pi_update();
+        }
+    }
+
+    /// Change the base priority of a server that has clients
+    pub(crate) fn pi_set_base_priority(&mut self, priority: prio_t) {
+        assert!(!self.tcbPIClients.empty());
+        self.tcbBasePriority = priority;
+        self.pi_update();
+    }
+
+    /// Recompute the effective priority of `self` and of the servers it is blocked on
+    ///
+    /// `self` must have clients, or just have lost its last one, so that `tcbBasePriority`
+    /// is valid.
+    pub(crate) fn pi_update(&mut self) {
+        let mut server = self;
+        loop {
+            // with no clients left this is the base priority, which ends the boost
+            let effective = server
+                .tcbPIClients
+                .iter()
+                .map(|client| client.tcbPriority)
+                .fold(server.tcbBasePriority, core::cmp::max);
+            if effective == server.tcbPriority {
+                return;
+            }
+            server.pi_set_effective_priority(effective);
+            if server.tcbPIServer == 0 {
+                return;
+            }
+            server = convert_to_mut_type_ref::<tcb_t>(server.tcbPIServer);
+        }
+    }
+
+    /// Move `self` to the queues of its new effective priority
+    fn pi_set_effective_priority(&mut self, priority: prio_t) {
+        let lowered = priority < self.tcbPriority;
+        let queued = self.tcbState.get_tcbQueued() != 0;
+        if queued {
+            self.sched_dequeue();
+        }
+        self.tcbPriority = priority;
+        if queued {
+            possible_switch_to(self);
+        } else if self.is_current() && lowered {
+            reschedule_required();
+        }
+        #[cfg(any(feature = "kernel_mcs", feature = "ep_priority_queue"))]
+        self.reorder_blocking_queue();
+    }
+}
@@ -0,0 +1,162 @@
+//! Pairing heap release queue for the MCS kernel, enabled by the `release_heap` feature.
+//!
+//! `ksReleaseQueue.head` is the root of the heap, the TCB with the earliest `Ready_Time()`, so
+//! `awaken` and `set_next_interrupt` read it like the head of the sorted list, and `tail` stays
+//! 0. Each TCB keeps its first child in `tcbReleaseChild`, its next sibling in `tcbSchedNext`
+//! and its previous sibling in `tcbSchedPrev`, or its parent if it is the first child. The
+//! root has no siblings, so its `tcbSchedPrev` is 0 as for the head of a list.
+//!
+//! `release_enqueue` melds the TCB with the root in O(1), and `release_remove` cuts the TCB
+//! out and melds its children back in amortized O(log n), which also pops the root.
+
+use sel4_common::platform::time_def::ticks_t;
+use sel4_common::utils::convert_to_mut_type_ref;
+
+use crate::{tcb_queue_t, tcb_t, SchedLink};
+
+#[inline]
+fn release_node(ptr: usize) -> &'static mut tcb_t {
+    convert_to_mut_type_ref::<tcb_t>(ptr)
+}
+
+#[inline]
+fn ready_time(ptr: usize) -> ticks_t {
+    release_node(ptr).Ready_Time()
+}
+
+/// Meld two heaps given by their roots and return the root of the result.
+///
+/// The root with the later ready time becomes the first child of the other one, and `a` wins
+/// ties, so a TCB melded into the heap is released after those with the same ready time.
+fn meld(a: usize, b: usize) -> usize {
+    let (parent, child) = if ready_time(b) < ready_time(a) {
+        (b, a)
+    } else {
+        (a, b)
+    };
+    let parent_tcb = release_node(parent);
+    let child_tcb = release_node(child);
+    child_tcb.tcbSchedNext = parent_tcb.tcbReleaseChild;
+    if parent_tcb.tcbReleaseChild != 0 {
+        release_node(parent_tcb.tcbReleaseChild).tcbSchedPrev = child;
+    }
+    child_tcb.tcbSchedPrev = parent;
+    parent_tcb.tcbReleaseChild = child;
+    parent_tcb.tcbSchedNext = 0;
+    parent_tcb.tcbSchedPrev = 0;
+    parent
+}
+
+/// Meld the siblings starting at `first` into one heap and return its root, 0 if there are
+/// none.
+fn merge_pairs(first: usize) -> usize {
+    // first pass: meld the siblings in pairs from left to right, stacking the results
+    // through tcbSchedNext
+    let mut stack = 0;
+    let mut current = first;
+    while current != 0 {
+        let second = release_node(current).tcbSchedNext;
+        if second == 0 {
+            release_node(current).tcbSchedPrev = 0;
+            release_node(current).tcbSchedNext = stack;
+            stack = current;
+            break;
+        }
+        let rest = release_node(second).tcbSchedNext;
+        let pair = meld(current, second);
+        release_node(pair).tcbSchedNext = stack;
+        stack = pair;
+        current = rest;
+    }
+    // second pass: meld the pairs from right to left, the stack holds the rightmost first
+    if stack == 0 {
+        return 0;
+    }
+    let mut root = stack;
+    let mut next = release_node(root).tcbSchedNext;
+    release_node(root).tcbSchedNext = 0;
+    while next != 0 {
+        let after = release_node(next).tcbSchedNext;
+        root = meld(root, next);
+        next = after;
+    }
+    root
+}
+
+impl tcb_queue_t<SchedLink> {
+    /// Insert a tcb into the release heap
+    pub fn release_heap_insert(&mut self, tcb: &mut tcb_t) {
+        tcb.tcbReleaseChild = 0;
+        tcb.tcbSchedNext = 0;
+        tcb.tcbSchedPrev = 0;
+        self.head = match self.head {
+            0 => tcb.get_ptr(),
+            root => meld(root, tcb.get_ptr()),
+        };
+    }
+
+    /// Remove a tcb from the release heap
+    pub fn release_heap_remove(&mut self, tcb: &mut tcb_t) {
+        let children = merge_pairs(tcb.tcbReleaseChild);
+        if self.head == tcb.get_ptr() {
+            self.head = children;
+        } else {
+            let prev = release_node(tcb.tcbSchedPrev);
+            if prev.tcbReleaseChild == tcb.get_ptr() {
+                prev.tcbReleaseChild = tcb.tcbSchedNext;
+            } else {
+                prev.tcbSchedNext = tcb.tcbSchedNext;
+            }
+            if tcb.tcbSchedNext != 0 {
+                release_node(tcb.tcbSchedNext).tcbSchedPrev = tcb.tcbSchedPrev;
+            }
+            if children != 0 {
+                self.head = meld(self.head, children);
+            }
+        }
+        tcb.tcbReleaseChild = 0;
+        tcb.tcbSchedNext = 0;
+        tcb.tcbSchedPrev = 0;
+    }
+
+    /// Iterate over the TCBs of the release heap, parents before their children
+    pub fn release_heap_iter(&self) -> impl Iterator<Item = &'static tcb_t> {
+        let root = self.head;
+        let mut next = root;
+        core::iter::from_fn(move || {
+            if next == 0 {
+                return None;
+            }
+            let tcb: &'static tcb_t = release_node(next);
+            next = if tcb.tcbReleaseChild != 0 {
+                tcb.tcbReleaseChild
+            } else {
+                // climb to the nearest ancestor with a next sibling
+                let mut node = tcb.get_ptr();
+                loop {
+                    if node == root {
+                        break 0;
+                    }
+                    let sibling = release_node(node).tcbSchedNext;
+                    if sibling != 0 {
+                        break sibling;
+                    }
+                    node = release_parent(node);
+                }
+            };
+            Some(tcb)
+        })
+    }
+}
+
+/// Get the parent of a TCB in the release heap that is not the root
+fn release_parent(ptr: usize) -> usize {
+    let mut node = ptr;
+    loop {
+        let prev = release_node(node).tcbSchedPrev;
+        if release_node(prev).tcbReleaseChild == node {
+            return prev;
+        }
+        node = prev;
+    }
+}
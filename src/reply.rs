@@ -46,6 +46,8 @@ impl reply {
         self.replyTCB = tcb_caller.get_ptr();
         tcb_caller.tcbState.set_replyObject(self.get_ptr() as u64);
         set_thread_state(tcb_caller, ThreadState::ThreadStateBlockedOnReply);
+        #[cfg(feature = "priority_inheritance")]
+        tcb_caller.pi_block_on(tcb_callee);
 
         if tcb_caller.tcbSchedContext != 0 && tcb_callee.tcbSchedContext == 0 && canDonate {
             let sc_donated = convert_to_mut_type_ref::<sched_context_t>(tcb_caller.tcbSchedContext);
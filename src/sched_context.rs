@@ -4,22 +4,29 @@ use core::{
 };
 
 use sel4_common::{
-    arch::{
-        get_kernel_wcet_ticks, get_kernel_wcet_us, get_max_ticks_to_us, get_max_us_to_ticks,
-        ticks_to_us, us_to_ticks, ArchReg::MsgInfo,
-    },
     message_info::seL4_MessageInfo_func,
     platform::time_def::{ticks_t, time_t},
-    sel4_config::{CONFIG_KERNEL_WCET_SCALE, UINT64_MAX},
+    sel4_config::{CONFIG_KERNEL_WCET_SCALE, CONFIG_MAX_NUM_NODES, UINT64_MAX},
     shared_types_bf_gen::seL4_MessageInfo,
-    structures_gen::{cap_sched_context_cap, notification, notification_t},
+    structures_gen::{call_stack, cap_sched_context_cap, notification, notification_t},
     utils::{convert_to_mut_type_ref, convert_to_option_mut_type_ref},
     BIT,
 };
 
+#[cfg(feature = "sc_admission")]
+use crate::admission::AdmissionError;
+use crate::arch::{
+    get_kernel_wcet_ticks, get_kernel_wcet_us, get_max_ticks_to_us, get_max_us_to_ticks,
+    ticks_to_us, us_to_ticks, ArchReg::MsgInfo,
+};
+#[cfg(feature = "debug_build")]
+use crate::dump::dump_refills;
+use crate::reply::reply_t;
+#[cfg(feature = "enable_smp")]
+use crate::NODE_STATE_ON_CORE;
 use crate::{
-    get_currenct_thread, get_current_sc, reschedule_required, tcb_t, NODE_STATE, SET_NODE_STATE,
-    SET_NODE_STATE_ON_CORE,
+    commit_time, get_currenct_thread, get_current_sc, possible_switch_to, reschedule_required,
+    tcb_t, NODE_STATE, SET_NODE_STATE, SET_NODE_STATE_ON_CORE,
 };
 
 pub type sched_context_t = sched_context;
@@ -39,8 +46,44 @@ pub struct sched_context {
     pub scRefillHead: usize,
     pub scRefillTail: usize,
     pub scSporadic: bool,
+    // the admission fields fill the padding after scSporadic, so the size does not change
+    #[cfg(feature = "sc_admission")]
+    /// The core the utilisation of the SC is accounted to
+    pub scAdmittedCore: u8,
+    #[cfg(feature = "sc_admission")]
+    /// The domain the utilisation of the SC is accounted to
+    pub scAdmittedDomain: u8,
+    #[cfg(feature = "sc_admission")]
+    /// The utilisation of the SC is accounted to `scAdmittedCore` and `scAdmittedDomain`
+    pub scAdmitted: bool,
+    #[cfg(feature = "sc_admission")]
+    /// The utilisation accounted for the SC, at most `UTILISATION_FULL`
+    pub scAdmittedUtilisation: u32,
 }
 pub const MIN_REFILLS: usize = 2;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+/// Reasons for rejecting a configuration in `sched_context_configure`.
+pub enum ConfigureError {
+    /// The core is `>= CONFIG_MAX_NUM_NODES`.
+    InvalidCore,
+    #[cfg(feature = "sc_admission")]
+    /// The utilisation of the SC cannot be admitted, see `sc_admit`.
+    Admission(AdmissionError),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+/// Reasons for rejecting a yield in `sched_context_yield_to`.
+pub enum YieldToError {
+    /// The SC is not bound to a TCB.
+    NoTcb,
+    /// The SC is bound to the caller itself.
+    YieldToSelf,
+    /// The priority of the TCB of the SC is above the MCP of the caller.
+    PriorityAboveMcp,
+    /// The caller is already yielding to an SC.
+    AlreadyYielding,
+}
 pub(crate) type refill_t = refill;
 #[repr(C)]
 #[derive(Debug, Clone)]
@@ -166,6 +209,8 @@ impl sched_context {
             (*self.refill_head()).rTime = NODE_STATE!(ksCurTime);
         }
         self.maybe_add_empty_tail();
+        #[cfg(feature = "debug_build")]
+        self.audit(budget);
     }
     #[inline]
     pub fn refill_head_overlapping(&mut self) -> bool {
@@ -184,6 +229,8 @@ impl sched_context {
             return;
         }
         if self.refill_ready() {
+            #[cfg(feature = "debug_build")]
+            let budget = self.refill_sum();
             #[allow(unused_unsafe)]
             unsafe {
                 (*self.refill_head()).rTime = NODE_STATE!(ksCurTime)
@@ -197,6 +244,8 @@ impl sched_context {
                 }
             }
             assert!(self.refill_sufficient(0));
+            #[cfg(feature = "debug_build")]
+            self.audit(budget);
         }
     }
     #[inline]
@@ -282,6 +331,8 @@ impl sched_context {
                 );
             }
         }
+        #[cfg(feature = "debug_build")]
+        self.audit(new_budget);
     }
     #[inline]
     pub fn schedule_used(&mut self, new_rTime: ticks_t, new_rAmount: ticks_t) {
@@ -298,6 +349,53 @@ impl sched_context {
         }
     }
 
+    #[cfg(feature = "debug_build")]
+    /// Check the invariants of the refills of the SC, which must hold `budget` in total, and
+    /// panic after dumping the refill list if one is broken.
+    ///
+    /// The head and tail must be below `scRefillMax`, the head must hold at least
+    /// `min_budget()`, and the refills of a sporadic SC must be ordered by time without
+    /// overlapping. It is called at the end of every refill operation, which gets `budget`
+    /// from `refill_sum` before it starts if it does not change the budget.
+    pub fn audit(&self, budget: ticks_t) {
+        if let Err(broken) = self.refill_audit(budget) {
+            dump_refills(self);
+            panic!(
+                "sc {:#x} fails the refill audit: {}",
+                self.get_ptr(),
+                broken
+            );
+        }
+    }
+    #[cfg(feature = "debug_build")]
+    fn refill_audit(&self, budget: ticks_t) -> Result<(), &'static str> {
+        if self.scRefillHead >= self.scRefillMax || self.scRefillTail >= self.scRefillMax {
+            return Err("head or tail is out of bounds");
+        }
+        let head = unsafe { &*self.refill_head() };
+        if head.rAmount < min_budget() {
+            return Err("head is below the minimum budget");
+        }
+        let mut sum = head.rAmount;
+        let mut current = self.scRefillHead;
+        // the indices are in bounds, so the walk ends within scRefillMax steps
+        while current != self.scRefillTail {
+            let next = self.refill_next(current);
+            let (prev, refill) =
+                unsafe { (&*self.refill_index(current), &*self.refill_index(next)) };
+            // the empty tail of a round robin SC shares the time of its head
+            if !self.is_round_robin() && prev.rTime + prev.rAmount > refill.rTime {
+                return Err("refills are out of order or overlap");
+            }
+            sum += refill.rAmount;
+            current = next;
+        }
+        if sum != budget {
+            return Err("refills do not add up to the budget");
+        }
+        Ok(())
+    }
+
     pub fn sched_context_resume(&mut self) {
         assert!(self.get_ptr() == 0 || self.scTcb != 0);
         if likely(self.get_ptr() != 0)
@@ -320,9 +418,7 @@ impl sched_context {
         tcb.tcbSchedContext = self.get_ptr();
         self.scTcb = tcb.get_ptr();
         #[cfg(feature = "enable_smp")]
-        unsafe {
-            crate::ffi::migrate_tcb(tcb, self.scCore);
-        }
+        tcb.migrate(self.scCore);
         if self.sc_sporadic() && self.sc_active() && !self.is_current() {
             self.refill_unblock_check()
         }
@@ -337,29 +433,122 @@ impl sched_context {
         if tcb.is_current() {
             reschedule_required();
         }
+        // the yielder would wait for a thread that no longer runs on the SC
+        if let Some(yielder) = convert_to_option_mut_type_ref::<tcb_t>(self.scYieldFrom) {
+            yielder.sched_context_cancel_yield_to();
+        }
         convert_to_mut_type_ref::<tcb_t>(self.scTcb).sched_dequeue();
         convert_to_mut_type_ref::<tcb_t>(self.scTcb).release_remove();
         convert_to_mut_type_ref::<tcb_t>(self.scTcb).tcbSchedContext = 0;
         self.scTcb = 0;
+        // an SC without a TCB does not use its bandwidth until it is configured again
+        #[cfg(feature = "sc_admission")]
+        self.sc_release_admission();
     }
     pub fn sched_context_unbind_all_tcbs(&mut self) {
         if self.scTcb != 0 {
+            convert_to_mut_type_ref::<tcb_t>(self.scTcb).remote_tcb_stall();
+            self.sched_context_unbind_tcb(convert_to_mut_type_ref::<tcb_t>(self.scTcb));
+        }
+    }
+    /// Detach the SC from its TCB, notification, reply and yielder before it is deleted, as
+    /// seL4 does when the last cap to the SC is finalised.
+    pub fn sched_context_finalise(&mut self) {
+        self.sched_context_unbind_all_tcbs();
+        self.sched_context_unbind_ntfn();
+        if self.scReply != 0 {
+            convert_to_mut_type_ref::<reply_t>(self.scReply).replyNext = call_stack::new(0, 0);
+            self.scReply = 0;
+        }
+        if let Some(yielder) = convert_to_option_mut_type_ref::<tcb_t>(self.scYieldFrom) {
+            yielder.schedContext_completeYieldTo();
+        }
+        self.scRefillMax = 0;
+        self.scSporadic = false;
+        #[cfg(feature = "sc_admission")]
+        self.sc_release_admission();
+    }
+    /// Configure the SC with `budget` every `period` and `max_refills` refills on `core`, as
+    /// seL4 invokeSchedControl_ConfigureFlags does. A `budget` equal to `period` makes a round
+    /// robin SC.
+    ///
+    /// The TCB of the SC is stalled and leaves the scheduler queues first, and the time the SC
+    /// consumed so far is charged, on whichever core it is current. Its refills are updated in
+    /// place if it stays active on the same core with a runnable TCB, and recreated otherwise.
+    /// The TCB then follows the SC to `core`, where it is queued if it can run. With `sc_admission` the SC is admitted for `_dom` on `core` first, and nothing
+    /// is changed if that fails.
+    pub fn sched_context_configure(
+        &mut self,
+        core: usize,
+        _dom: usize,
+        budget: ticks_t,
+        period: ticks_t,
+        max_refills: usize,
+    ) -> Result<(), ConfigureError> {
+        if core >= CONFIG_MAX_NUM_NODES {
+            return Err(ConfigureError::InvalidCore);
+        }
+        #[cfg(feature = "sc_admission")]
+        self.sc_admit(
+            core,
+            _dom,
+            budget,
+            if budget == period { 0 } else { period },
+        )
+        .map_err(ConfigureError::Admission)?;
+        if let Some(tcb) = convert_to_option_mut_type_ref::<tcb_t>(self.scTcb) {
+            // the stall puts a TCB running on another core back into its ready queue
+            tcb.remote_tcb_stall();
+            tcb.release_remove();
+            tcb.sched_dequeue();
+            // bill the consumed time before the refills change
+            if self.is_current() {
+                commit_time();
+            }
             #[cfg(feature = "enable_smp")]
-            unsafe {
-                crate::ffi::remote_tcb_stall(convert_to_mut_type_ref::<tcb_t>(self.scTcb));
+            if !self.is_current() && NODE_STATE_ON_CORE!(self.scCore, ksCurSC) == self.get_ptr() {
+                self.charge_remote();
             }
-            self.sched_context_unbind_tcb(convert_to_mut_type_ref::<tcb_t>(self.scTcb));
         }
+        // only an SC in use by a runnable TCB has bandwidth to preserve
+        let in_use = convert_to_option_mut_type_ref::<tcb_t>(self.scTcb)
+            .is_some_and(|tcb| tcb.is_runnable());
+        if budget == period {
+            self.refill_new(MIN_REFILLS, budget, 0);
+        } else if self.sc_active() && self.scCore == core && in_use {
+            self.refill_update(period, budget, max_refills);
+        } else {
+            self.refill_new(max_refills, budget, period);
+        }
+        self.scCore = core;
+        if let Some(tcb) = convert_to_option_mut_type_ref::<tcb_t>(self.scTcb) {
+            if tcb.tcbAffinity != core {
+                tcb.migrate(core);
+            }
+            self.sched_context_resume();
+            #[cfg(feature = "enable_smp")]
+            let local = core == sel4_common::utils::cpu_id();
+            #[cfg(not(feature = "enable_smp"))]
+            let local = true;
+            if local {
+                if tcb.is_runnable() && !tcb.is_current() {
+                    possible_switch_to(tcb);
+                }
+            } else if tcb.is_schedulable() {
+                tcb.sched_enqueue();
+            }
+            if tcb.is_current() {
+                reschedule_required();
+            }
+        }
+        Ok(())
     }
     pub fn sched_context_donate(&mut self, to: &mut tcb_t) {
         assert!(self.get_ptr() != 0);
         assert!(to.get_ptr() != 0);
         assert!(to.tcbSchedContext == 0);
         if let Some(from) = convert_to_option_mut_type_ref::<tcb_t>(self.scTcb) {
-            #[cfg(feature = "enable_smp")]
-            unsafe {
-                crate::ffi::remote_tcb_stall(from);
-            }
+            from.remote_tcb_stall();
             from.sched_dequeue();
             from.release_remove();
             from.tcbSchedContext = 0;
@@ -370,9 +559,116 @@ impl sched_context {
         self.scTcb = to.get_ptr();
         to.tcbSchedContext = self.get_ptr();
         #[cfg(feature = "enable_smp")]
-        unsafe {
-            crate::ffi::migrate_tcb(to, self.scCore);
+        to.migrate(self.scCore);
+    }
+    /// Move the SC and its bound TCB to `new_core`, as seL4 invokeSchedControl_ConfigureFlags
+    /// does when the core changes.
+    ///
+    /// The TCB is stalled once and leaves the ready or release queue of the old core. If the
+    /// SC is current on the old core, its consumed time is committed when that is this core,
+    /// and otherwise charged from the old core's `ksConsumed`, which then reschedules. The refills are then recreated with
+    /// `refill_new` on `new_core`, keeping the release time of an SC whose head refill is not
+    /// ready yet, so that the move does not skip its period. The TCB then goes into the
+    /// release queue of `new_core` if the SC is not ready, and into its ready queue otherwise,
+    /// and both cores reprogram their timers. With `sc_admission` an admitted SC is admitted on
+    /// `new_core` first, which releases its utilisation on the old core, and nothing is
+    /// changed if that fails.
+    pub fn sched_context_set_core(&mut self, new_core: usize) -> Result<(), ConfigureError> {
+        if new_core >= CONFIG_MAX_NUM_NODES {
+            return Err(ConfigureError::InvalidCore);
+        }
+        let old_core = self.scCore;
+        if old_core == new_core {
+            return Ok(());
+        }
+        #[cfg(feature = "sc_admission")]
+        if self.scAdmitted && self.sc_active() {
+            let period = self.scPeriod;
+            let budget = self.refill_sum();
+            self.sc_admit(new_core, self.scAdmittedDomain as usize, budget, period)
+                .map_err(ConfigureError::Admission)?;
+        }
+        if let Some(tcb) = convert_to_option_mut_type_ref::<tcb_t>(self.scTcb) {
+            tcb.remote_tcb_stall();
+            tcb.sched_dequeue();
+            tcb.release_remove();
+        }
+        if self.is_current() {
+            commit_time();
+            reschedule_required();
+        }
+        #[cfg(feature = "enable_smp")]
+        if !self.is_current() && NODE_STATE_ON_CORE!(old_core, ksCurSC) == self.get_ptr() {
+            self.charge_remote();
+        }
+        if self.sc_active() {
+            let budget = self.refill_sum();
+            let release = unsafe { (*self.refill_head()).rTime };
+            self.refill_new(self.scRefillMax, budget, self.scPeriod);
+            if !self.is_round_robin() && release > NODE_STATE!(ksCurTime) {
+                unsafe { (*self.refill_head()).rTime = release };
+            }
+        }
+        self.scCore = new_core;
+        if let Some(tcb) = convert_to_option_mut_type_ref::<tcb_t>(self.scTcb) {
+            tcb.migrate(new_core);
+            self.sched_context_resume();
+            if tcb.is_schedulable() {
+                tcb.sched_append();
+            }
+        }
+        SET_NODE_STATE_ON_CORE!(old_core, ksReprogram = true);
+        SET_NODE_STATE_ON_CORE!(new_core, ksReprogram = true);
+        Ok(())
+    }
+    /// Yield the rest of the timeslice of `caller`, the current thread, to the TCB of the SC.
+    ///
+    /// If that TCB is schedulable on this core with a priority at least that of `caller`, it
+    /// is queued in front of `caller`, which waits for it to run, and `caller` gets the time
+    /// consumed by the SC once it runs again. Otherwise the TCB is only moved to the head of
+    /// its ready queue if it is schedulable, and `caller` gets the consumed time right away.
+    pub fn sched_context_yield_to(&mut self, caller: &mut tcb_t) -> Result<(), YieldToError> {
+        assert!(caller.is_current());
+        let target = match convert_to_option_mut_type_ref::<tcb_t>(self.scTcb) {
+            Some(target) => target,
+            None => return Err(YieldToError::NoTcb),
+        };
+        if target.get_ptr() == caller.get_ptr() {
+            return Err(YieldToError::YieldToSelf);
+        }
+        if target.tcbPriority > caller.tcbMCP {
+            return Err(YieldToError::PriorityAboveMcp);
+        }
+        if caller.tcbYieldTo != 0 {
+            return Err(YieldToError::AlreadyYielding);
+        }
+        if let Some(yielder) = convert_to_option_mut_type_ref::<tcb_t>(self.scYieldFrom) {
+            yielder.schedContext_completeYieldTo();
+        }
+        // from here on the target is ready and sufficient if it is schedulable
+        self.sched_context_resume();
+        if target.is_schedulable() {
+            #[cfg(feature = "enable_smp")]
+            let remote = self.scCore != sel4_common::utils::cpu_id();
+            #[cfg(not(feature = "enable_smp"))]
+            let remote = false;
+            if remote || target.tcbPriority < caller.tcbPriority {
+                // seL4 SCHED_ENQUEUE, the target goes to the head of its queue
+                target.sched_dequeue();
+                target.sched_enqueue();
+            } else {
+                caller.tcbYieldTo = self.get_ptr();
+                self.scYieldFrom = caller.get_ptr();
+                target.sched_dequeue();
+                caller.sched_enqueue();
+                target.sched_enqueue();
+                reschedule_required();
+                // the consumed time is set once the caller runs again
+                return Ok(());
+            }
         }
+        self.set_consumed();
+        Ok(())
     }
     pub fn sched_context_bind_ntfn(&mut self, ntfn: &mut notification_t) {
         ntfn.set_ntfnSchedContext(self.get_ptr() as u64);
@@ -402,37 +698,76 @@ impl sched_context {
             return ticks_to_us(consumed);
         }
     }
-}
-pub fn refill_budget_check(mut usage: ticks_t) {
-    unsafe {
-        let sc = get_current_sc();
-        assert!(!sc.is_round_robin());
+    /// Charge `usage` to the refills of the SC, which must not be round robin.
+    pub fn refill_budget_check(&mut self, mut usage: ticks_t) {
+        unsafe {
+            assert!(!self.is_round_robin());
+            #[cfg(feature = "debug_build")]
+            let budget = self.refill_sum();
 
-        while (*sc.refill_head()).rAmount <= usage && (*sc.refill_head()).rTime < max_release_time()
-        {
-            usage -= (*sc.refill_head()).rAmount;
+            while (*self.refill_head()).rAmount <= usage
+                && (*self.refill_head()).rTime < max_release_time()
+            {
+                usage -= (*self.refill_head()).rAmount;
 
-            if sc.refill_single() {
-                (*sc.refill_head()).rTime += sc.scPeriod;
-            } else {
-                let old_head = sc.refill_pop_head();
-                (*old_head).rTime += sc.scPeriod;
-                sc.schedule_used((*old_head).rTime, (*old_head).rAmount);
+                if self.refill_single() {
+                    (*self.refill_head()).rTime += self.scPeriod;
+                } else {
+                    let old_head = self.refill_pop_head();
+                    (*old_head).rTime += self.scPeriod;
+                    self.schedule_used((*old_head).rTime, (*old_head).rAmount);
+                }
             }
-        }
-        if usage > 0 && (*sc.refill_head()).rTime < max_release_time() {
-            assert!((*sc.refill_head()).rAmount > usage);
-            let new_rTime = (*sc.refill_head()).rTime + sc.scPeriod;
-            let new_rAmount = usage;
+            if usage > 0 && (*self.refill_head()).rTime < max_release_time() {
+                assert!((*self.refill_head()).rAmount > usage);
+                let new_rTime = (*self.refill_head()).rTime + self.scPeriod;
+                let new_rAmount = usage;
 
-            (*sc.refill_head()).rAmount -= usage;
-            (*sc.refill_head()).rTime += usage;
-            sc.schedule_used(new_rTime, new_rAmount);
+                (*self.refill_head()).rAmount -= usage;
+                (*self.refill_head()).rTime += usage;
+                self.schedule_used(new_rTime, new_rAmount);
+            }
+            while (*self.refill_head()).rAmount < min_budget() {
+                let head = self.refill_pop_head();
+                (*self.refill_head()).rAmount += (*head).rAmount;
+                (*self.refill_head()).rTime -= (*head).rAmount;
+            }
+            #[cfg(feature = "debug_build")]
+            self.audit(budget);
         }
-        while (*sc.refill_head()).rAmount < min_budget() {
-            let head = sc.refill_pop_head();
-            (*sc.refill_head()).rAmount += (*head).rAmount;
-            (*sc.refill_head()).rTime -= (*head).rAmount;
+    }
+    /// Charge `consumed` to the SC, which ran for that long as the current SC of its core.
+    ///
+    /// A round robin SC gets its whole budget back at the head, any other SC schedules the
+    /// used budget with `refill_budget_check`.
+    pub fn charge_consumed(&mut self, consumed: ticks_t) {
+        if self.is_round_robin() {
+            assert!(self.refill_size() == MIN_REFILLS);
+            #[cfg(feature = "debug_build")]
+            let budget = self.refill_sum();
+            unsafe {
+                (*self.refill_head()).rAmount += (*self.refill_tail()).rAmount;
+                (*self.refill_tail()).rAmount = 0;
+            }
+            #[cfg(feature = "debug_build")]
+            self.audit(budget);
+        } else {
+            self.refill_budget_check(consumed);
         }
+        assert!(unsafe { (*self.refill_head()).rAmount } >= min_budget());
+        self.scConsumed += consumed;
+    }
+    #[cfg(feature = "enable_smp")]
+    /// Charge the time consumed on `scCore`, another core that runs the SC, and have that core
+    /// reschedule, seL4 chargeBudget for a remote core followed by doReschedule.
+    fn charge_remote(&mut self) {
+        let core = self.scCore;
+        self.charge_consumed(NODE_STATE_ON_CORE!(core, ksConsumed));
+        SET_NODE_STATE_ON_CORE!(core, ksConsumed = 0);
+        unsafe { crate::ksSMP[sel4_common::utils::cpu_id()].ipiReschedulePending |= BIT!(core) };
     }
 }
+/// Charge `usage` to the current SC, seL4 refill_budget_check.
+pub fn refill_budget_check(usage: ticks_t) {
+    get_current_sc().refill_budget_check(usage)
+}
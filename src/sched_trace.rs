@@ -0,0 +1,172 @@
+//! Scheduler event tracing, enabled by the `sched_trace` feature.
+//!
+//! Each core records its scheduler events in its own fixed-size ring buffer. When the buffer
+//! is full the oldest event is overwritten and counted as lost. The buffers can be copied out
+//! with `sched_trace_snapshot` or emptied with `sched_trace_drain`.
+
+use crate::arch::{timer, Timer_func};
+use crate::tcb_t;
+use sel4_common::sel4_config::CONFIG_MAX_NUM_NODES;
+#[cfg(feature = "enable_smp")]
+use sel4_common::utils::cpu_id;
+
+/// Number of events kept per core.
+pub const SCHED_TRACE_ENTRIES: usize = 256;
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The kind of a scheduler event.
+pub enum SchedEventKind {
+    /// `sched_enqueue` or `sched_append` put the TCB in a ready queue.
+    Enqueue = 0,
+    /// `sched_dequeue` removed the TCB from a ready queue.
+    Dequeue = 1,
+    /// `switch_to_this` made the TCB the current thread.
+    Switch = 2,
+    /// `reschedule_required` was called while the TCB was current.
+    RescheduleRequired = 3,
+    /// `next_domain` switched to the domain in `arg`.
+    DomainSwitch = 4,
+    /// `release_enqueue` put the TCB in the release queue.
+    ReleaseEnqueue = 5,
+    /// `awaken` released the TCB from the release queue.
+    Awaken = 6,
+    /// `charge_budget` charged `arg` ticks to the current TCB.
+    ChargeBudget = 7,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// A recorded scheduler event.
+pub struct sched_event_t {
+    /// The timer value when the event was recorded.
+    pub timestamp: usize,
+    /// The core that recorded the event.
+    pub cpu: usize,
+    pub kind: SchedEventKind,
+    /// The TCB the event is about, 0 for domain switches.
+    pub tcb: usize,
+    /// The priority of the TCB at the time of the event.
+    pub prio: usize,
+    /// Event specific data, see `SchedEventKind`.
+    pub arg: usize,
+}
+
+impl sched_event_t {
+    const EMPTY: sched_event_t = sched_event_t {
+        timestamp: 0,
+        cpu: 0,
+        kind: SchedEventKind::Enqueue,
+        tcb: 0,
+        prio: 0,
+        arg: 0,
+    };
+}
+
+#[derive(Clone, Copy)]
+/// The ring buffer of one core.
+pub struct sched_trace_t {
+    entries: [sched_event_t; SCHED_TRACE_ENTRIES],
+    /// Index of the oldest event.
+    start: usize,
+    /// Number of valid events.
+    len: usize,
+    /// Number of events overwritten before they were drained.
+    lost: usize,
+}
+
+impl sched_trace_t {
+    const EMPTY: sched_trace_t = sched_trace_t {
+        entries: [sched_event_t::EMPTY; SCHED_TRACE_ENTRIES],
+        start: 0,
+        len: 0,
+        lost: 0,
+    };
+
+    fn push(&mut self, event: sched_event_t) {
+        let end = (self.start + self.len) % SCHED_TRACE_ENTRIES;
+        self.entries[end] = event;
+        if self.len == SCHED_TRACE_ENTRIES {
+            self.start = (self.start + 1) % SCHED_TRACE_ENTRIES;
+            self.lost += 1;
+        } else {
+            self.len += 1;
+        }
+    }
+
+    fn copy_to(&self, out: &mut [sched_event_t]) -> usize {
+        let count = core::cmp::min(self.len, out.len());
+        for (i, slot) in out.iter_mut().take(count).enumerate() {
+            *slot = self.entries[(self.start + i) % SCHED_TRACE_ENTRIES];
+        }
+        count
+    }
+}
+
+pub static mut ksSchedTrace: [sched_trace_t; CONFIG_MAX_NUM_NODES] =
+    [sched_trace_t::EMPTY; CONFIG_MAX_NUM_NODES];
+
+#[inline]
+fn current_cpu() -> usize {
+    #[cfg(feature = "enable_smp")]
+    {
+        cpu_id()
+    }
+    #[cfg(not(feature = "enable_smp"))]
+    {
+        0
+    }
+}
+
+/// Record an event on the current core.
+pub fn sched_trace_record(kind: SchedEventKind, tcb: usize, prio: usize, arg: usize) {
+    let cpu = current_cpu();
+    let event = sched_event_t {
+        timestamp: timer.get_current_time() as usize,
+        cpu,
+        kind,
+        tcb,
+        prio,
+        arg,
+    };
+    unsafe { ksSchedTrace[cpu].push(event) }
+}
+
+#[inline]
+/// Record an event about `tcb` on the current core.
+pub fn sched_trace_tcb(kind: SchedEventKind, tcb: &tcb_t, arg: usize) {
+    sched_trace_record(kind, tcb.get_ptr(), tcb.tcbPriority, arg);
+}
+
+/// Copy the events of `cpu` into `out`, oldest first, and keep them in the buffer.
+///
+/// Returns the number of events copied.
+pub fn sched_trace_snapshot(cpu: usize, out: &mut [sched_event_t]) -> usize {
+    unsafe { ksSchedTrace[cpu].copy_to(out) }
+}
+
+/// Move the events of `cpu` into `out`, oldest first.
+///
+/// Returns the number of events moved. Events that do not fit stay in the buffer.
+pub fn sched_trace_drain(cpu: usize, out: &mut [sched_event_t]) -> usize {
+    unsafe {
+        let trace = &mut ksSchedTrace[cpu];
+        let count = trace.copy_to(out);
+        trace.start = (trace.start + count) % SCHED_TRACE_ENTRIES;
+        trace.len -= count;
+        count
+    }
+}
+
+/// Get the number of events of `cpu` that were overwritten before being drained.
+pub fn sched_trace_lost(cpu: usize) -> usize {
+    unsafe { ksSchedTrace[cpu].lost }
+}
+
+/// Drop all events of `cpu` and reset its lost counter.
+pub fn sched_trace_clear(cpu: usize) {
+    unsafe {
+        ksSchedTrace[cpu].start = 0;
+        ksSchedTrace[cpu].len = 0;
+        ksSchedTrace[cpu].lost = 0;
+    }
+}
@@ -7,11 +7,12 @@
 #![allow(unused_unsafe)]
 #![allow(static_mut_ref)]
 
+use crate::arch::ArchReg;
 #[cfg(feature = "enable_smp")]
 use crate::deps::do_mask_reschedule;
+#[cfg(not(feature = "host_sim"))]
 use core::arch::asm;
 use core::intrinsics::{likely, unlikely};
-use sel4_common::arch::ArchReg;
 #[cfg(feature = "enable_smp")]
 use sel4_common::sel4_config::CONFIG_MAX_NUM_NODES;
 use sel4_common::sel4_config::{
@@ -23,22 +24,28 @@ use sel4_common::utils::cpu_id;
 use sel4_common::utils::{convert_to_mut_type_ref, ptr_to_usize_add};
 use sel4_common::{BIT, MASK};
 
+#[cfg(feature = "kernel_mcs")]
+use crate::arch::us_to_ticks;
 use crate::deps::ksIdleThreadTCB;
+use crate::dump::dump_all_threads;
 #[cfg(feature = "kernel_mcs")]
 use crate::sched_context::{sched_context_t, MIN_REFILLS};
+#[cfg(feature = "sched_trace")]
+use crate::sched_trace::{sched_trace_record, sched_trace_tcb, SchedEventKind};
 use crate::tcb::{set_thread_state, tcb_t};
-use crate::tcb_queue::tcb_queue_t;
+use crate::tcb_queue::{tcb_queue_t, SchedLink};
 use crate::thread_state::ThreadState;
 #[cfg(feature = "kernel_mcs")]
 use crate::{deps::ksIdleThreadSC, sched_context::refill_budget_check, tcb_release_dequeue};
+#[cfg(feature = "tickless")]
+use sel4_common::platform::time_def::ticks_t;
 #[cfg(feature = "kernel_mcs")]
 use sel4_common::{
-    arch::us_to_ticks,
     platform::time_def::{ticks_t, time_t, US_IN_MS},
     sel4_config::CONFIG_BOOT_THREAD_TIME_SLICE,
     utils::convert_to_mut_type_ref_unsafe,
 };
-#[cfg(target_arch = "aarch64")]
+#[cfg(all(target_arch = "aarch64", not(feature = "host_sim")))]
 use sel4_vspace::{
     get_arm_global_user_vspace_base, kpptr_to_paddr, set_current_user_vspace_root, ttbr_new,
 };
@@ -51,7 +58,7 @@ cfg_if::cfg_if! {
             /// Number of pending IPI (Inter-Processor Interrupt) reschedule requests.
             pub ipiReschedulePending: usize,
             /// Array of ready queues for each domain and priority level.
-            pub ksReadyQueues: [tcb_queue_t; NUM_READY_QUEUES],
+            pub ksReadyQueues: [tcb_queue_t<SchedLink>; NUM_READY_QUEUES],
             /// Bitmap representing the presence of ready queues at the L1 level for each domain.
             pub ksReadyQueuesL1Bitmap: [usize; CONFIG_NUM_DOMAINS],
             /// Bitmap representing the presence of ready queues at the L2 level for each domain and priority level.
@@ -64,7 +71,7 @@ cfg_if::cfg_if! {
             pub ksSchedulerAction: usize,
             /// MCS relative field
             #[cfg(feature = "kernel_mcs")]
-            pub ksReleaseQueue: tcb_queue_t,
+            pub ksReleaseQueue: tcb_queue_t<SchedLink>,
             #[cfg(feature = "kernel_mcs")]
             pub ksConsumed: time_t,
             #[cfg(feature = "kernel_mcs")]
@@ -79,19 +86,31 @@ cfg_if::cfg_if! {
             pub ksActiveFPUState: usize,
             // TODO: Cache Line 对齐
             pub ks_fpu_restore_since_switch: usize,
+            /// Number of timer ticks accounted on this core.
+            #[cfg(feature = "benchmark_track_utilisation")]
+            pub ksBenchmarkTotalTicks: usize,
+            /// The live TCBs of this core.
+            #[cfg(feature = "debug_build")]
+            pub ksDebugTCBs: tcb_queue_t<crate::DebugLink>,
+            /// The time up to which ticks have been charged on this core.
+            #[cfg(feature = "tickless")]
+            pub ksTickStart: ticks_t,
+            /// Number of threads of each domain in the ready queues of this core.
+            #[cfg(feature = "smp_load_balance")]
+            pub ksReadyThreads: [usize; CONFIG_NUM_DOMAINS],
         }
 
         #[no_mangle]
         pub static mut ksSMP: [SmpStateData; CONFIG_MAX_NUM_NODES] = [SmpStateData {
             ipiReschedulePending: 0,
-            ksReadyQueues: [tcb_queue_t { head: 0, tail: 0 }; NUM_READY_QUEUES],
+            ksReadyQueues: [tcb_queue_t::new(); NUM_READY_QUEUES],
             ksReadyQueuesL1Bitmap: [0; CONFIG_NUM_DOMAINS],
             ksReadyQueuesL2Bitmap: [[0; L2_BITMAP_SIZE]; CONFIG_NUM_DOMAINS],
             ksCurThread: 0,
             ksIdleThread: 0,
             ksSchedulerAction: 1,
             #[cfg(feature = "kernel_mcs")]
-            ksReleaseQueue: tcb_queue_t { head: 0, tail: 0 },
+            ksReleaseQueue: tcb_queue_t::new(),
             #[cfg(feature = "kernel_mcs")]
             ksConsumed: 0,
             #[cfg(feature = "kernel_mcs")]
@@ -104,11 +123,19 @@ cfg_if::cfg_if! {
             ksIdleSC: 0,
             ksActiveFPUState: 0,
             ks_fpu_restore_since_switch: 0,
+            #[cfg(feature = "benchmark_track_utilisation")]
+            ksBenchmarkTotalTicks: 0,
+            #[cfg(feature = "debug_build")]
+            ksDebugTCBs: tcb_queue_t::new(),
+            #[cfg(feature = "tickless")]
+            ksTickStart: 0,
+            #[cfg(feature = "smp_load_balance")]
+            ksReadyThreads: [0; CONFIG_NUM_DOMAINS],
         }; CONFIG_MAX_NUM_NODES];
     } else {
         #[no_mangle]
-        pub static mut ksReadyQueues: [tcb_queue_t; NUM_READY_QUEUES] =
-            [tcb_queue_t { head: 0, tail: 0 }; NUM_READY_QUEUES];
+        pub static mut ksReadyQueues: [tcb_queue_t<SchedLink>; NUM_READY_QUEUES] =
+            [tcb_queue_t::new(); NUM_READY_QUEUES];
 
         #[no_mangle]
         pub static mut ksReadyQueuesL2Bitmap: [[usize; L2_BITMAP_SIZE]; CONFIG_NUM_DOMAINS] =
@@ -134,9 +161,13 @@ cfg_if::cfg_if! {
         #[no_mangle]
         pub static mut ks_fpu_restore_since_switch: usize = 0;
 
+        #[cfg(feature = "debug_build")]
+        #[no_mangle]
+        pub static mut ksDebugTCBs: tcb_queue_t<crate::DebugLink> = tcb_queue_t::new();
+
         #[no_mangle]
         #[cfg(feature = "kernel_mcs")]
-        pub static mut ksReleaseQueue: tcb_queue_t = tcb_queue_t { head: 0, tail: 0 };
+        pub static mut ksReleaseQueue: tcb_queue_t<SchedLink> = tcb_queue_t::new();
 
         #[no_mangle]
         #[cfg(feature = "kernel_mcs")]
@@ -157,6 +188,18 @@ cfg_if::cfg_if! {
         #[no_mangle]
         #[cfg(feature = "kernel_mcs")]
         pub static mut ksIdleSC: usize = 0;
+
+        #[no_mangle]
+        #[cfg(feature = "benchmark_track_utilisation")]
+        pub static mut ksBenchmarkTotalTicks: usize = 0;
+
+        #[no_mangle]
+        #[cfg(feature = "tickless")]
+        pub static mut ksTickStart: ticks_t = 0;
+
+        #[no_mangle]
+        #[cfg(feature = "smp_load_balance")]
+        pub static mut ksReadyThreads: [usize; CONFIG_NUM_DOMAINS] = [0; CONFIG_NUM_DOMAINS];
     }
 }
 
@@ -170,9 +213,34 @@ pub struct dschedule_t {
     pub length: usize,
 }
 
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+/// Reasons for rejecting a domain schedule in `set_domain_schedule`.
+pub enum DomScheduleError {
+    /// The schedule has no entries.
+    Empty,
+    /// The schedule has more than `KS_DOM_SCHEDULE_MAX_LENGTH` entries.
+    TooLong,
+    /// The entry at this index names a domain `>= CONFIG_NUM_DOMAINS`.
+    InvalidDomain(usize),
+    /// The entry at this index has a zero length.
+    ZeroLength(usize),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+/// Reasons for rejecting a default timeslice length in `set_time_slice_band`.
+pub enum TimeSliceError {
+    /// The band is `>= TIME_SLICE_BANDS`.
+    InvalidBand,
+    /// The length is zero.
+    ZeroLength,
+}
+
 pub const SCHEDULER_ACTION_RESUME_CURRENT_THREAD: usize = 0;
 pub const SCHEDULER_ACTION_CHOOSE_NEW_THREAD: usize = 1;
-pub const KS_DOM_SCHEDULE_LENGTH: usize = 1;
+/// Capacity of the domain schedule table.
+pub const KS_DOM_SCHEDULE_MAX_LENGTH: usize = 256;
+/// Number of priority bands with their own default timeslice length.
+pub const TIME_SLICE_BANDS: usize = 8;
 
 pub const SCHED_CONTEXT_NO_FLAGS: usize = 0;
 pub const SCHED_CONTEXT_SPORADIC: usize = 1;
@@ -189,11 +257,23 @@ pub static mut ksDomScheduleIdx: usize = 0;
 #[link_section = ".boot.bss"]
 pub static mut ksWorkUnitsCompleted: usize = 0;
 
+/// Number of valid entries in `ksDomSchedule`.
+#[no_mangle]
+pub static mut ksDomScheduleLength: usize = 1;
+
 // #[link_section = ".boot.bss"]
-pub static mut ksDomSchedule: [dschedule_t; KS_DOM_SCHEDULE_LENGTH] = [dschedule_t {
-    domain: 0,
-    length: 60,
-}; KS_DOM_SCHEDULE_LENGTH];
+pub static mut ksDomSchedule: [dschedule_t; KS_DOM_SCHEDULE_MAX_LENGTH] = {
+    let mut schedule = [dschedule_t {
+        domain: 0,
+        length: 0,
+    }; KS_DOM_SCHEDULE_MAX_LENGTH];
+    schedule[0].length = 60;
+    schedule
+};
+
+/// Default timeslice length in ticks of each priority band, for the TCBs without their own.
+#[no_mangle]
+pub static mut ksTimeSliceBands: [usize; TIME_SLICE_BANDS] = [CONFIG_TIME_SLICE; TIME_SLICE_BANDS];
 
 #[allow(non_camel_case_types)]
 pub type prio_t = usize;
@@ -431,10 +511,214 @@ pub fn remove_from_bigmap(_cpu: usize, dom: usize, prio: usize) {
     }
 }
 
+#[cfg(feature = "debug_build")]
+/// Check that the ready queues, their bitmaps and the queued TCBs agree with each other,
+/// and panic with the offending core, domain and priority if they do not.
+///
+/// For every queue this checks that the `tcbSchedPrev`/`tcbSchedNext` links are consistent
+/// and end at `tail`, that every TCB in it is marked `tcbQueued` with the queue's domain and
+/// priority, and that the L1/L2 bitmap bits are set exactly for the non-empty queues.
+pub fn check_scheduler_invariants() {
+    #[cfg(feature = "enable_smp")]
+    for cpu in 0..CONFIG_MAX_NUM_NODES {
+        check_ready_queues_on_core(cpu);
+    }
+    #[cfg(not(feature = "enable_smp"))]
+    check_ready_queues_on_core(0);
+}
+
+#[cfg(feature = "debug_build")]
+fn check_ready_queues_on_core(_cpu: usize) {
+    for dom in 0..CONFIG_NUM_DOMAINS {
+        let (l1_bitmap, l2_bitmap) = unsafe {
+            #[cfg(feature = "enable_smp")]
+            {
+                (
+                    ksSMP[_cpu].ksReadyQueuesL1Bitmap[dom],
+                    ksSMP[_cpu].ksReadyQueuesL2Bitmap[dom],
+                )
+            }
+            #[cfg(not(feature = "enable_smp"))]
+            {
+                (ksReadyQueuesL1Bitmap[dom], ksReadyQueuesL2Bitmap[dom])
+            }
+        };
+        for l1index in 0..L2_BITMAP_SIZE {
+            let l2_word = l2_bitmap[invert_l1index(l1index)];
+            assert!(
+                (l1_bitmap & BIT!(l1index) != 0) == (l2_word != 0),
+                "cpu {} dom {}: L1 bit {} is {:#x} but L2 word is {:#x}",
+                _cpu,
+                dom,
+                l1index,
+                l1_bitmap,
+                l2_word
+            );
+        }
+        for prio in 0..CONFIG_NUM_PRIORITIES {
+            let queue = unsafe {
+                #[cfg(feature = "enable_smp")]
+                {
+                    ksSMP[_cpu].ksReadyQueues[ready_queues_index(dom, prio)]
+                }
+                #[cfg(not(feature = "enable_smp"))]
+                {
+                    ksReadyQueues[ready_queues_index(dom, prio)]
+                }
+            };
+            let l2_bit = l2_bitmap[invert_l1index(prio_to_l1index(prio))]
+                & BIT!(prio & MASK!(WORD_RADIX))
+                != 0;
+            assert!(
+                l2_bit == !queue.empty(),
+                "cpu {} dom {} prio {}: L2 bit is {} but queue head is {:#x}",
+                _cpu,
+                dom,
+                prio,
+                l2_bit,
+                queue.head
+            );
+            check_ready_queue(_cpu, dom, prio, &queue);
+        }
+    }
+}
+
+#[cfg(feature = "debug_build")]
+fn check_ready_queue(_cpu: usize, dom: usize, prio: usize, queue: &tcb_queue_t<SchedLink>) {
+    assert!(
+        (queue.head == 0) == (queue.tail == 0),
+        "cpu {} dom {} prio {}: queue head {:#x} and tail {:#x} disagree",
+        _cpu,
+        dom,
+        prio,
+        queue.head,
+        queue.tail
+    );
+    let mut prev = 0;
+    let mut current = queue.head;
+    // A cycle always revisits a TCB whose prev link differs from the one we came from,
+    // so the prev check below also guarantees this loop terminates.
+    while current != 0 {
+        let tcb = convert_to_mut_type_ref::<tcb_t>(current);
+        assert!(
+            tcb.tcbSchedPrev == prev,
+            "cpu {} dom {} prio {}: tcb {:#x} has prev {:#x}, expected {:#x}",
+            _cpu,
+            dom,
+            prio,
+            current,
+            tcb.tcbSchedPrev,
+            prev
+        );
+        assert!(
+            tcb.tcbState.get_tcbQueued() == 1,
+            "cpu {} dom {} prio {}: tcb {:#x} is in the queue but not marked queued",
+            _cpu,
+            dom,
+            prio,
+            current
+        );
+        assert!(
+            tcb.domain == dom && tcb.tcbPriority == prio,
+            "cpu {} dom {} prio {}: tcb {:#x} has dom {} prio {}",
+            _cpu,
+            dom,
+            prio,
+            current,
+            tcb.domain,
+            tcb.tcbPriority
+        );
+        #[cfg(feature = "enable_smp")]
+        assert!(
+            tcb.tcbAffinity == _cpu,
+            "cpu {} dom {} prio {}: tcb {:#x} has affinity {}",
+            _cpu,
+            dom,
+            prio,
+            current,
+            tcb.tcbAffinity
+        );
+        prev = current;
+        current = tcb.tcbSchedNext;
+    }
+    assert!(
+        queue.tail == prev,
+        "cpu {} dom {} prio {}: queue tail is {:#x} but the last tcb is {:#x}",
+        _cpu,
+        dom,
+        prio,
+        queue.tail,
+        prev
+    );
+}
+
+#[inline]
+/// Get the timeslice band of a priority.
+pub fn time_slice_band(prio: prio_t) -> usize {
+    prio / (CONFIG_NUM_PRIORITIES / TIME_SLICE_BANDS)
+}
+
+/// Set the default timeslice length, in ticks, of the priorities in `band`.
+///
+/// It applies to the TCBs of the band without their own length from their next timeslice on.
+pub fn set_time_slice_band(band: usize, length: usize) -> Result<(), TimeSliceError> {
+    if band >= TIME_SLICE_BANDS {
+        return Err(TimeSliceError::InvalidBand);
+    }
+    if length == 0 {
+        return Err(TimeSliceError::ZeroLength);
+    }
+    unsafe { ksTimeSliceBands[band] = length };
+    Ok(())
+}
+
+#[inline]
+/// Get the default timeslice length, in ticks, of the priorities in `band`.
+pub fn get_time_slice_band(band: usize) -> usize {
+    unsafe { ksTimeSliceBands[band] }
+}
+
+/// Install a new domain schedule.
+///
+/// Every entry must name a domain below `CONFIG_NUM_DOMAINS` and have a non-zero length.
+/// On success the table replaces the current one, and the next scheduling decision
+/// starts from its first entry.
+pub fn set_domain_schedule(schedule: &[dschedule_t]) -> Result<(), DomScheduleError> {
+    if schedule.is_empty() {
+        return Err(DomScheduleError::Empty);
+    }
+    if schedule.len() > KS_DOM_SCHEDULE_MAX_LENGTH {
+        return Err(DomScheduleError::TooLong);
+    }
+    for (i, entry) in schedule.iter().enumerate() {
+        if entry.domain >= CONFIG_NUM_DOMAINS {
+            return Err(DomScheduleError::InvalidDomain(i));
+        }
+        if entry.length == 0 {
+            return Err(DomScheduleError::ZeroLength(i));
+        }
+    }
+    unsafe {
+        ksDomSchedule[..schedule.len()].copy_from_slice(schedule);
+        ksDomScheduleLength = schedule.len();
+        // next_domain() advances before reading, so the first entry is used next.
+        ksDomScheduleIdx = schedule.len() - 1;
+        ksDomainTime = 0;
+    }
+    reschedule_required();
+    Ok(())
+}
+
+#[inline]
+/// Get the installed domain schedule.
+pub fn get_domain_schedule() -> &'static [dschedule_t] {
+    unsafe { &ksDomSchedule[..ksDomScheduleLength] }
+}
+
 fn next_domain() {
     unsafe {
         ksDomScheduleIdx += 1;
-        if ksDomScheduleIdx >= KS_DOM_SCHEDULE_LENGTH {
+        if ksDomScheduleIdx >= ksDomScheduleLength {
             ksDomScheduleIdx = 0;
         }
         #[cfg(feature = "kernel_mcs")]
@@ -451,6 +735,8 @@ fn next_domain() {
         {
             ksDomainTime = ksDomSchedule[ksDomScheduleIdx].length;
         }
+        #[cfg(feature = "sched_trace")]
+        sched_trace_record(SchedEventKind::DomainSwitch, 0, 0, ksCurDomain);
         //FIXME ksWorkUnits not used;
         // ksWorkUnits
     }
@@ -471,7 +757,11 @@ fn schedule_choose_new_thread() {
 
 fn choose_thread() {
     unsafe {
-        let dom = 0;
+        let dom = ksCurDomain;
+        #[cfg(feature = "smp_load_balance")]
+        if NODE_STATE!(ksReadyThreads)[dom] == 0 {
+            crate::load_balance::pull_work(sel4_common::utils::cpu_id());
+        }
         let ks_l1_bit = {
             #[cfg(feature = "enable_smp")]
             {
@@ -509,15 +799,17 @@ fn choose_thread() {
             }
             convert_to_mut_type_ref::<tcb_t>(thread).switch_to_this();
         } else {
-            #[cfg(target_arch = "aarch64")]
+            // the idle thread runs in the global user vspace, the rest of the switch is shared
+            #[cfg(all(target_arch = "aarch64", not(feature = "host_sim")))]
             {
                 set_current_user_vspace_root(ttbr_new(
                     0,
                     kpptr_to_paddr(get_arm_global_user_vspace_base()),
                 ));
-                set_current_thread(get_idle_thread());
+                get_idle_thread().switch_to_this_in_vspace();
             }
-            #[cfg(target_arch = "riscv64")]
+            // the simulation has no vspace to reset, so it switches like riscv64
+            #[cfg(any(target_arch = "riscv64", feature = "host_sim"))]
             get_idle_thread().switch_to_this();
         }
     }
@@ -534,6 +826,8 @@ pub fn reschedule_required() {
     }
     // ksSchedulerAction = SCHEDULER_ACTION_CHOOSE_NEW_THREAD;
     SET_NODE_STATE!(ksSchedulerAction = SCHEDULER_ACTION_CHOOSE_NEW_THREAD);
+    #[cfg(feature = "sched_trace")]
+    trace_reschedule_required();
 }
 #[no_mangle]
 #[cfg(feature = "kernel_mcs")]
@@ -553,6 +847,16 @@ pub fn reschedule_required() {
         }
     }
     SET_NODE_STATE!(ksSchedulerAction = SCHEDULER_ACTION_CHOOSE_NEW_THREAD);
+    #[cfg(feature = "sched_trace")]
+    trace_reschedule_required();
+}
+
+#[cfg(feature = "sched_trace")]
+fn trace_reschedule_required() {
+    // reschedule_required can run before the current thread is set while booting
+    if NODE_STATE!(ksCurThread) != 0 {
+        sched_trace_tcb(SchedEventKind::RescheduleRequired, get_currenct_thread(), 0);
+    }
 }
 #[cfg(feature = "kernel_mcs")]
 pub fn awaken() {
@@ -579,6 +883,8 @@ pub fn awaken() {
                 convert_to_mut_type_ref::<sched_context_t>((*awakened).tcbSchedContext)
                     .refill_sufficient(0)
             );
+            #[cfg(feature = "sched_trace")]
+            sched_trace_tcb(SchedEventKind::Awaken, &*awakened, 0);
             possible_switch_to(&mut *awakened);
         }
     }
@@ -590,11 +896,9 @@ pub fn is_cur_domain_expired() -> bool {
 }
 #[cfg(feature = "kernel_mcs")]
 pub fn update_timestamp() {
-    use sel4_common::{
-        platform::{timer, Timer_func},
-        sel4_config::NUM_DOMAINS,
-    };
+    use sel4_common::sel4_config::NUM_DOMAINS;
 
+    use crate::arch::{timer, Timer_func};
     use crate::sched_context::{max_release_time, min_budget};
 
     unsafe {
@@ -656,11 +960,8 @@ pub fn mcs_preemption_point() {
 }
 #[cfg(feature = "kernel_mcs")]
 pub fn set_next_interrupt() {
-    use sel4_common::{
-        arch::get_timer_precision,
-        platform::{timer, Timer_func},
-        sel4_config::NUM_DOMAINS,
-    };
+    use crate::arch::{get_timer_precision, timer, Timer_func};
+    use sel4_common::sel4_config::NUM_DOMAINS;
 
     unsafe {
         let mut next_interrupt = NODE_STATE!(ksCurTime)
@@ -686,25 +987,16 @@ pub fn set_next_interrupt() {
 }
 #[cfg(feature = "kernel_mcs")]
 pub fn charge_budget(consumed: ticks_t, canTimeoutFault: bool) {
-    use crate::{endTimeslice, sched_context::min_budget};
+    use crate::endTimeslice;
 
     unsafe {
         if likely(NODE_STATE!(ksCurSC) != NODE_STATE!(ksIdleSC)) {
-            let current_sched_context = get_current_sc();
-            if current_sched_context.is_round_robin() {
-                assert!(current_sched_context.refill_size() == MIN_REFILLS);
-                (*current_sched_context.refill_head()).rAmount +=
-                    (*current_sched_context.refill_tail()).rAmount;
-                (*current_sched_context.refill_tail()).rAmount = 0;
-            } else {
-                refill_budget_check(consumed);
-            }
-
-            assert!((*current_sched_context.refill_head()).rAmount >= min_budget());
-            current_sched_context.scConsumed += consumed;
+            get_current_sc().charge_consumed(consumed);
         }
         SET_NODE_STATE!(ksConsumed = 0);
         let thread = get_currenct_thread();
+        #[cfg(feature = "sched_trace")]
+        sched_trace_tcb(SchedEventKind::ChargeBudget, thread, consumed as usize);
         if likely(thread.is_schedulable()) {
             assert!(thread.tcbSchedContext == NODE_STATE!(ksCurSC));
             endTimeslice(canTimeoutFault);
@@ -726,8 +1018,12 @@ pub fn commit_time() {
 
                 if current_sched_context.is_round_robin() {
                     assert!(current_sched_context.refill_size() == MIN_REFILLS);
+                    #[cfg(feature = "debug_build")]
+                    let budget = current_sched_context.refill_sum();
                     (*current_sched_context.refill_head()).rAmount -= NODE_STATE!(ksConsumed);
                     (*current_sched_context.refill_tail()).rAmount += NODE_STATE!(ksConsumed);
+                    #[cfg(feature = "debug_build")]
+                    current_sched_context.audit(budget);
                 } else {
                     refill_budget_check(NODE_STATE!(ksConsumed));
                 }
@@ -772,6 +1068,8 @@ pub fn schedule() {
         awaken();
         check_domain_time();
     }
+    #[cfg(feature = "tickless")]
+    crate::tickless::tickless_account();
     if NODE_STATE!(ksSchedulerAction) != SCHEDULER_ACTION_RESUME_CURRENT_THREAD {
         let was_runnable: bool;
         let current_tcb = get_currenct_thread();
@@ -790,7 +1088,13 @@ pub fn schedule() {
             assert!(candidate.is_schedulable());
             let fastfail = get_currenct_thread().get_ptr() == get_idle_thread().get_ptr()
                 || candidate.tcbPriority < get_currenct_thread().tcbPriority;
-            if fastfail && !is_highest_prio(unsafe { ksCurDomain }, candidate.tcbPriority) {
+            let choose_new =
+                fastfail && !is_highest_prio(unsafe { ksCurDomain }, candidate.tcbPriority);
+            // a queued thread of an EDF priority may have an earlier deadline than the candidate
+            #[cfg(feature = "edf_band")]
+            let choose_new =
+                choose_new || crate::edf::edf_queued_before(candidate, unsafe { ksCurDomain });
+            if choose_new {
                 candidate.sched_enqueue();
                 // ksSchedulerAction = SCHEDULER_ACTION_CHOOSE_NEW_THREAD;
                 SET_NODE_STATE!(ksSchedulerAction = SCHEDULER_ACTION_CHOOSE_NEW_THREAD);
@@ -818,6 +1122,10 @@ pub fn schedule() {
             SET_NODE_STATE!(ksReprogram = false);
         }
     }
+    #[cfg(feature = "tickless")]
+    crate::tickless::tickless_reprogram();
+    #[cfg(feature = "sched_invariants")]
+    check_scheduler_invariants();
 }
 
 #[inline]
@@ -855,6 +1163,11 @@ pub fn possible_switch_to(target: &mut tcb_t) {
                 reschedule_required();
                 target.sched_enqueue();
             } else {
+                #[cfg(feature = "edf_band")]
+                if crate::edf::edf_defers(target) {
+                    target.sched_enqueue();
+                    return;
+                }
                 SET_NODE_STATE!(ksSchedulerAction = target.get_ptr());
             }
         }
@@ -885,6 +1198,11 @@ pub fn possible_switch_to(target: &mut tcb_t) {
                 reschedule_required();
                 target.sched_enqueue();
             } else {
+                #[cfg(feature = "edf_band")]
+                if crate::edf::edf_defers(target) {
+                    target.sched_enqueue();
+                    return;
+                }
                 SET_NODE_STATE!(ksSchedulerAction = target.get_ptr());
             }
         }
@@ -894,27 +1212,72 @@ pub fn possible_switch_to(target: &mut tcb_t) {
 #[no_mangle]
 /// Schedule current thread if time slice is expired.
 pub fn timer_tick() {
+    #[cfg(not(feature = "tickless"))]
+    consume_ticks(1);
+    #[cfg(feature = "tickless")]
+    crate::tickless::tickless_account();
+    #[cfg(feature = "smp_load_balance")]
+    crate::load_balance::load_balance_tick();
+}
+
+/// Charge `ticks` timer ticks to the current thread and to the current domain.
+///
+/// The timeslice is refilled with the timeslice length of the thread each time it runs out, as
+/// if the ticks had come one by one.
+pub(crate) fn consume_ticks(ticks: usize) {
+    #[cfg(feature = "benchmark_track_utilisation")]
+    crate::benchmark::benchmark_utilisation_tick(ticks);
     let current = get_currenct_thread();
     // if hart_id() == 0 {
     //     debug!("timer tick current: {:#x}", current.get_ptr());
     // }
 
     if likely(current.get_state() == ThreadState::ThreadStateRunning) {
-        if current.tcbTimeSlice > 1 {
+        if current.tcbTimeSlice > ticks {
             // if hart_id() == 0 {
             //     debug!("tcbTimeSlice : {}", current.tcbTimeSlice);
             // }
-            current.tcbTimeSlice -= 1;
+            current.tcbTimeSlice -= ticks;
         } else {
             // if hart_id() == 0 {
             //     debug!("switch");
             // }
 
-            current.tcbTimeSlice = CONFIG_TIME_SLICE;
+            let length = current.get_time_slice_length();
+            let overrun = ticks - current.tcbTimeSlice;
+            current.tcbTimeSlice = length - overrun % length;
             current.sched_append();
             reschedule_required();
         }
     }
+
+    #[cfg(not(feature = "kernel_mcs"))]
+    if CONFIG_NUM_DOMAINS > 1 {
+        tick_domain_time(ticks);
+    }
+}
+
+#[cfg(not(feature = "kernel_mcs"))]
+/// Consume `ticks` ticks of the current domain's time, and force a reschedule at the domain
+/// boundary.
+fn tick_domain_time(ticks: usize) {
+    // ksDomainTime is shared by all cores, so only the boot core consumes it.
+    #[cfg(feature = "enable_smp")]
+    if cpu_id() != 0 {
+        return;
+    }
+    unsafe {
+        ksDomainTime = ksDomainTime.saturating_sub(ticks);
+        if ksDomainTime == 0 {
+            reschedule_required();
+            // every other core has to leave the expired domain as well
+            #[cfg(feature = "enable_smp")]
+            {
+                ksSMP[cpu_id()].ipiReschedulePending |=
+                    MASK!(CONFIG_MAX_NUM_NODES) & !BIT!(cpu_id());
+            }
+        }
+    }
 }
 
 #[no_mangle]
@@ -951,19 +1314,40 @@ pub fn activateThread() {
         }
         // 诡异的语法...
         ThreadState::ThreadStateIdleThreadState => return {},
-        #[cfg(not(feature = "enable_smp"))]
-        _ => panic!(
-            "current thread is blocked , state id :{}",
-            thread.get_state() as usize
-        ),
-        #[cfg(feature = "enable_smp")]
-        _ => panic!(
-            "current thread is blocked , state id :{}, cpu: {}",
-            thread.get_state() as usize,
-            thread.tcbAffinity
-        ),
+        _ => {
+            // log every thread before giving up, the state of the others explains the block
+            dump_all_threads();
+            #[cfg(not(feature = "enable_smp"))]
+            panic!(
+                "current thread {:#x} \"{}\" is blocked , state: {:?}",
+                thread.get_ptr(),
+                thread_name(thread),
+                thread.get_state()
+            );
+            #[cfg(feature = "enable_smp")]
+            panic!(
+                "current thread {:#x} \"{}\" is blocked , state: {:?}, cpu: {}",
+                thread.get_ptr(),
+                thread_name(thread),
+                thread.get_state(),
+                thread.tcbAffinity
+            );
+        }
     }
 }
+#[inline]
+/// Get the name of the thread for messages, empty without `debug_build`
+pub(crate) fn thread_name(_thread: &tcb_t) -> &'static str {
+    #[cfg(feature = "debug_build")]
+    {
+        _thread.get_name()
+    }
+    #[cfg(not(feature = "debug_build"))]
+    {
+        ""
+    }
+}
+
 #[cfg(feature = "kernel_mcs")]
 pub fn configure_sched_context(tcb: &mut tcb_t, sc_pptr: &mut sched_context_t, timeslice: ticks_t) {
     tcb.tcbSchedContext = sc_pptr.get_ptr();
@@ -983,6 +1367,11 @@ pub fn create_idle_thread() {
         // Arch_configureIdleThread(tcb.tcbArch);
         tcb.tcbArch.config_idle_thread(idle_thread as usize, 0);
         set_thread_state(tcb, ThreadState::ThreadStateIdleThreadState);
+        #[cfg(feature = "debug_build")]
+        {
+            tcb.debug_append();
+            tcb.set_name("idle_thread");
+        }
         #[cfg(feature = "kernel_mcs")]
         {
             tcb.tcbYieldTo = 0;
@@ -1009,6 +1398,11 @@ pub fn create_idle_thread() {
             tcb.tcbArch.config_idle_thread(idle_thread as usize, i);
             set_thread_state(tcb, ThreadState::ThreadStateIdleThreadState);
             tcb.tcbAffinity = i;
+            #[cfg(feature = "debug_build")]
+            {
+                tcb.debug_append();
+                tcb.set_name("idle_thread");
+            }
             #[cfg(feature = "kernel_mcs")]
             {
                 tcb.tcbYieldTo = 0;
@@ -1029,7 +1423,10 @@ pub fn idle_thread() {
     unsafe {
         loop {
             // debug!("hello idle_thread");
+            #[cfg(not(feature = "host_sim"))]
             asm!("wfi");
+            #[cfg(feature = "host_sim")]
+            core::hint::spin_loop();
         }
     }
 }
@@ -38,3 +38,26 @@ impl Default for lookupSlot_ret_t {
         }
     }
 }
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// A fault tag that `set_fault_mrs` has no message encoding for
+pub struct UnknownFault(pub usize);
+
+/// Message registers of a timeout fault
+pub const TIMEOUT_DATA: usize = 0;
+pub const TIMEOUT_CONSUMED: usize = 1;
+
+/// Message registers of a debug exception fault
+pub const DEBUG_EXCEPTION_FAULT_IP: usize = 0;
+pub const DEBUG_EXCEPTION_EXCEPTION_REASON: usize = 1;
+pub const DEBUG_EXCEPTION_TRIGGER_ADDRESS: usize = 2;
+pub const DEBUG_EXCEPTION_BREAKPOINT_NUMBER: usize = 3;
+
+/// Debug exception reasons that carry no trigger address or breakpoint number
+pub const SINGLE_STEP: usize = 2;
+pub const SOFTWARE_BREAK_REQUEST: usize = 3;
+
+/// Message registers of the ARM hypervisor faults
+pub const VGIC_MAINTENANCE_IDX: usize = 0;
+pub const VCPU_FAULT_HSR: usize = 0;
+pub const VPPI_EVENT_IRQ: usize = 0;
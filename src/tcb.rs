@@ -1,13 +1,20 @@
+use crate::arch::{
+    set_vm_root, vm_rights_t, ArchReg, ArchTCB, MSG_REGISTER_NUM, N_EXCEPTON_MESSAGE,
+    N_SYSCALL_MESSAGE,
+};
+#[cfg(feature = "benchmark_track_utilisation")]
+use crate::benchmark::{benchmark_util_t, benchmark_utilisation_switch};
+#[cfg(feature = "edf_band")]
+use crate::edf::is_edf_priority;
 use crate::prio_t;
-use crate::tcb_queue::tcb_queue_t;
+#[cfg(feature = "sched_trace")]
+use crate::sched_trace::{sched_trace_tcb, SchedEventKind};
+use crate::tcb_queue::{link_from_ptr, tcb_queue_t, SchedLink, TcbLink};
 #[cfg(feature = "kernel_mcs")]
 use crate::{
     sched_context::sched_context_t, NODE_STATE, NODE_STATE_ON_CORE, SET_NODE_STATE_ON_CORE,
 };
 use core::intrinsics::{likely, unlikely};
-use sel4_common::arch::{
-    vm_rights_t, ArchReg, ArchTCB, MSG_REGISTER_NUM, N_EXCEPTON_MESSAGE, N_SYSCALL_MESSAGE,
-};
 use sel4_common::fault::*;
 use sel4_common::ffi::current_fault;
 use sel4_common::message_info::seL4_MessageInfo_func;
@@ -21,7 +28,6 @@ use sel4_common::structures_gen::{
 #[cfg(not(feature = "kernel_mcs"))]
 use sel4_common::structures_gen::{cap_reply_cap, mdb_node};
 use sel4_common::utils::{convert_to_mut_type_ref, pageBitsForSize};
-#[cfg(feature = "enable_smp")]
 use sel4_common::BIT;
 use sel4_common::MASK;
 #[cfg(feature = "kernel_mcs")]
@@ -29,13 +35,23 @@ use sel4_common::{platform::time_def::ticks_t, utils::convert_to_option_mut_type
 #[cfg(not(feature = "kernel_mcs"))]
 use sel4_cspace::interface::cte_insert;
 use sel4_cspace::interface::{cte_t, resolve_address_bits};
-use sel4_vspace::{pptr_t, set_vm_root};
+use sel4_vspace::pptr_t;
 
 use super::scheduler::{
-    add_to_bitmap, get_current_thread_on_node, possible_switch_to, ready_queues_index,
-    remove_from_bigmap, reschedule_required, schedule_tcb, set_current_thread,
+    add_to_bitmap, get_current_thread_on_node, get_time_slice_band, possible_switch_to,
+    ready_queues_index, remove_from_bigmap, reschedule_required, schedule_tcb, set_current_thread,
+    time_slice_band,
 };
-use super::structures::lookupSlot_raw_ret_t;
+use super::structures::{lookupSlot_raw_ret_t, UnknownFault};
+#[cfg(feature = "hardware_debug_api")]
+use super::structures::{
+    DEBUG_EXCEPTION_BREAKPOINT_NUMBER, DEBUG_EXCEPTION_EXCEPTION_REASON, DEBUG_EXCEPTION_FAULT_IP,
+    DEBUG_EXCEPTION_TRIGGER_ADDRESS, SINGLE_STEP, SOFTWARE_BREAK_REQUEST,
+};
+#[cfg(feature = "kernel_mcs")]
+use super::structures::{TIMEOUT_CONSUMED, TIMEOUT_DATA};
+#[cfg(feature = "arm_hypervisor_support")]
+use super::structures::{VCPU_FAULT_HSR, VGIC_MAINTENANCE_IDX, VPPI_EVENT_IRQ};
 
 use super::thread_state::*;
 
@@ -81,6 +97,48 @@ pub struct tcb_t {
     pub tcbEPNext: usize,
     /// The previous TCB in the EP queue
     pub tcbEPPrev: usize,
+    #[cfg(feature = "release_heap")]
+    /// The first child of the TCB in the release heap
+    pub tcbReleaseChild: usize,
+    #[cfg(feature = "benchmark_track_utilisation")]
+    /// The utilisation counters of the TCB
+    pub benchmark: benchmark_util_t,
+    #[cfg(feature = "priority_inheritance")]
+    /// The priority of the TCB without inherited priorities, valid while it has PI clients
+    pub tcbBasePriority: usize,
+    #[cfg(feature = "priority_inheritance")]
+    /// The server holding the reply cap this TCB is blocked on
+    pub tcbPIServer: usize,
+    #[cfg(feature = "priority_inheritance")]
+    /// The clients blocked on a reply cap held by this TCB
+    pub tcbPIClients: tcb_queue_t<crate::PILink>,
+    #[cfg(feature = "priority_inheritance")]
+    /// The next TCB in the PI client list of the server
+    pub tcbPINext: usize,
+    #[cfg(feature = "priority_inheritance")]
+    /// The previous TCB in the PI client list of the server
+    pub tcbPIPrev: usize,
+    #[cfg(feature = "smp_load_balance")]
+    /// The TCB stays on its core when the load is balanced
+    pub tcbPinned: bool,
+    #[cfg(feature = "have_fpu")]
+    /// The lazy FPU restore score of the TCB, see `fpu_eager`
+    pub tcbFpuRestores: usize,
+    /// The timeslice length of the TCB in ticks, 0 for the default of its priority band.
+    /// It comes last so that the fields shared with seL4 keep their offsets.
+    pub tcbTimeSliceLength: usize,
+}
+
+// the tcb_t sits at TCB_OFFSET in its object, so every optional field must still fit
+const _: () = assert!(core::mem::size_of::<tcb_t>() <= BIT!(SEL4_TCB_BITS) - TCB_OFFSET);
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+/// Reasons for rejecting a new core in `set_affinity`.
+pub enum AffinityError {
+    /// The core does not exist.
+    InvalidCore,
+    /// The scheduling context of the TCB is on another core.
+    SchedContextOnOtherCore,
 }
 
 impl tcb_t {
@@ -109,9 +167,24 @@ impl tcb_t {
     }
 
     #[inline]
-    /// Get the current state of the TCB
+    /// Get the current state of the TCB, panicking if `tsType` is corrupted
     pub fn get_state(&self) -> ThreadState {
-        unsafe { core::mem::transmute::<u8, ThreadState>(self.tcbState.get_tsType() as u8) }
+        match self.try_get_state() {
+            Ok(state) => state,
+            Err(InvalidThreadState(ts_type)) => {
+                panic!(
+                    "tcb {:#x}: invalid thread state {}",
+                    self.get_ptr(),
+                    ts_type
+                )
+            }
+        }
+    }
+
+    #[inline]
+    /// Get the current state of the TCB
+    pub fn try_get_state(&self) -> Result<ThreadState, InvalidThreadState> {
+        ThreadState::try_from(self.tcbState.get_tsType() as u64)
     }
 
     #[inline]
@@ -175,6 +248,11 @@ impl tcb_t {
     #[cfg(not(feature = "kernel_mcs"))]
     /// Set the priority of the TCB, and reschedule if the thread is runnable and not current
     pub fn set_priority(&mut self, priority: prio_t) {
+        #[cfg(feature = "priority_inheritance")]
+        if !self.tcbPIClients.empty() {
+            self.pi_set_base_priority(priority);
+            return;
+        }
         self.sched_dequeue();
         self.tcbPriority = priority;
         if self.is_runnable() {
@@ -184,14 +262,19 @@ impl tcb_t {
                 possible_switch_to(self)
             }
         }
+        #[cfg(feature = "ep_priority_queue")]
+        self.reorder_blocking_queue();
+        #[cfg(feature = "priority_inheritance")]
+        self.pi_propagate();
     }
     #[inline]
     #[cfg(feature = "kernel_mcs")]
     pub fn set_priority(&mut self, priority: prio_t) {
-        use sel4_common::structures_gen::{endpoint_t, notification_t};
-
-        use crate::{reorder_ep, reorder_ntfn};
-
+        #[cfg(feature = "priority_inheritance")]
+        if !self.tcbPIClients.empty() {
+            self.pi_set_base_priority(priority);
+            return;
+        }
         match self.get_state() {
             ThreadState::ThreadStateRunning | ThreadState::ThreadStateRestart => {
                 if self.tcbState.get_tcbQueued() != 0 || self.is_current() {
@@ -203,32 +286,47 @@ impl tcb_t {
                     self.tcbPriority = priority;
                 }
             }
-            ThreadState::ThreadStateBlockedOnReceive | ThreadState::ThreadStateBlockedOnSend => {
-                self.tcbPriority = priority;
-                unsafe {
-                    reorder_ep(
-                        convert_to_mut_type_ref::<endpoint_t>(
-                            self.tcbState.get_blockingObject() as usize
-                        ),
-                        self,
-                    )
-                };
-            }
-            ThreadState::ThreadStateBlockedOnNotification => {
+            ThreadState::ThreadStateBlockedOnReceive
+            | ThreadState::ThreadStateBlockedOnSend
+            | ThreadState::ThreadStateBlockedOnNotification => {
                 self.tcbPriority = priority;
-                unsafe {
-                    reorder_ntfn(
-                        convert_to_mut_type_ref::<notification_t>(
-                            self.tcbState.get_blockingObject() as usize,
-                        ),
-                        self,
-                    )
-                };
+                self.reorder_blocking_queue();
             }
             _ => {
                 self.tcbPriority = priority;
             }
         }
+        #[cfg(feature = "priority_inheritance")]
+        self.pi_propagate();
+    }
+
+    #[cfg(any(feature = "kernel_mcs", feature = "ep_priority_queue"))]
+    /// Move the TCB to the position of its new priority in the endpoint or notification
+    /// queue it is blocked on
+    pub(crate) fn reorder_blocking_queue(&mut self) {
+        use sel4_common::structures_gen::{endpoint_t, notification_t};
+
+        use crate::{reorder_ep, reorder_ntfn};
+
+        match self.get_state() {
+            ThreadState::ThreadStateBlockedOnReceive | ThreadState::ThreadStateBlockedOnSend => {
+                reorder_ep(
+                    convert_to_mut_type_ref::<endpoint_t>(
+                        self.tcbState.get_blockingObject() as usize
+                    ),
+                    self,
+                );
+            }
+            ThreadState::ThreadStateBlockedOnNotification => {
+                reorder_ntfn(
+                    convert_to_mut_type_ref::<notification_t>(
+                        self.tcbState.get_blockingObject() as usize
+                    ),
+                    self,
+                );
+            }
+            _ => {}
+        }
     }
 
     #[inline]
@@ -245,6 +343,23 @@ impl tcb_t {
         self.tcbBoundNotification = 0;
     }
 
+    #[inline]
+    /// Get the timeslice length of the TCB in ticks, its own or else the default of its
+    /// priority band
+    pub fn get_time_slice_length(&self) -> usize {
+        match self.tcbTimeSliceLength {
+            0 => get_time_slice_band(time_slice_band(self.tcbPriority)),
+            length => length,
+        }
+    }
+
+    /// Set the timeslice length of the TCB in ticks, 0 for the default of its priority band.
+    /// The remaining timeslice is cut down to the new length.
+    pub fn set_time_slice_length(&mut self, length: usize) {
+        self.tcbTimeSliceLength = length;
+        self.tcbTimeSlice = self.tcbTimeSlice.min(self.get_time_slice_length());
+    }
+
     #[inline]
     /// Set the domain of the TCB.
     pub fn set_domain(&mut self, dom: usize) {
@@ -259,6 +374,81 @@ impl tcb_t {
         }
     }
 
+    /// Move the TCB to `new_core`, the TCB must not be in a ready or release queue.
+    pub fn migrate(&mut self, new_core: usize) {
+        assert!(self.tcbState.get_tcbQueued() == 0);
+        #[cfg(feature = "have_fpu")]
+        crate::fpu::fpu_release(self);
+        self.debug_remove();
+        self.tcbAffinity = new_core;
+        self.debug_append();
+    }
+
+    /// Stop the TCB from running on another core, seL4 remoteTCBStall.
+    ///
+    /// If the TCB is the current thread of another core, that core is sent a stall IPI, after
+    /// which the TCB is back in its ready queue, and the core is marked for a reschedule IPI
+    /// so that it picks a new thread. In the single core build there is no other core to
+    /// stall.
+    pub fn remote_tcb_stall(&self) {
+        #[cfg(feature = "enable_smp")]
+        {
+            #[cfg(feature = "kernel_mcs")]
+            if self.tcbSchedContext == 0 {
+                return;
+            }
+            let cpu = sel4_common::utils::cpu_id();
+            if self.tcbAffinity != cpu && self.is_current() {
+                unsafe {
+                    use super::scheduler::ksSMP;
+                    crate::deps::do_remote_stall(self.tcbAffinity);
+                    ksSMP[cpu].ipiReschedulePending |= BIT!(self.tcbAffinity);
+                }
+            }
+        }
+    }
+
+    /// Set the core the TCB runs on.
+    ///
+    /// If the TCB is running on another core, that core is stalled first, which puts the TCB
+    /// back into its ready queue. The TCB is then removed from the queues of the old core and,
+    /// if it can run, appended to the ready queue of `new_core`, which gets a reschedule IPI
+    /// when needed. With `smp_load_balance` the TCB is also pinned, so that the balancer
+    /// leaves it there.
+    ///
+    /// Nothing is changed if `new_core` does not exist, or with MCS if the scheduling context
+    /// of the TCB is not on `new_core`.
+    pub fn set_affinity(&mut self, new_core: usize) -> Result<(), AffinityError> {
+        if new_core >= CONFIG_MAX_NUM_NODES {
+            return Err(AffinityError::InvalidCore);
+        }
+        #[cfg(feature = "kernel_mcs")]
+        if let Some(sc) = convert_to_option_mut_type_ref::<sched_context_t>(self.tcbSchedContext) {
+            if sc.scCore != new_core {
+                return Err(AffinityError::SchedContextOnOtherCore);
+            }
+        }
+        self.remote_tcb_stall();
+        let was_current = self.is_current();
+        self.sched_dequeue();
+        #[cfg(feature = "kernel_mcs")]
+        self.release_remove();
+        self.migrate(new_core);
+        #[cfg(feature = "kernel_mcs")]
+        if let Some(sc) = convert_to_option_mut_type_ref::<sched_context_t>(self.tcbSchedContext) {
+            sc.sched_context_resume();
+        }
+        if self.is_schedulable() {
+            self.sched_append();
+        }
+        #[cfg(feature = "smp_load_balance")]
+        self.set_pinned(true);
+        if was_current {
+            reschedule_required();
+        }
+        Ok(())
+    }
+
     /// Enqueue the TCB to the scheduling queue
     pub fn sched_enqueue(&mut self) {
         // let thread = self as *mut tcb_t as usize;
@@ -282,6 +472,13 @@ impl tcb_t {
                 add_to_bitmap(self.get_cpu(), dom, prio);
             }
 
+            #[cfg(feature = "edf_band")]
+            if is_edf_priority(prio) {
+                queue.edf_insert(self, true);
+            } else {
+                queue.prepend(self);
+            }
+            #[cfg(not(feature = "edf_band"))]
             queue.prepend(self);
             // if queue.tail == 0 {
             //     queue.tail = self_ptr as usize;
@@ -306,6 +503,10 @@ impl tcb_t {
                 }
             }
             self.tcbState.set_tcbQueued(1);
+            #[cfg(feature = "smp_load_balance")]
+            crate::load_balance::count_ready(self.get_cpu(), dom, true);
+            #[cfg(feature = "sched_trace")]
+            sched_trace_tcb(SchedEventKind::Enqueue, self, 0);
         }
 
         #[cfg(feature = "enable_smp")]
@@ -314,7 +515,7 @@ impl tcb_t {
 
     #[inline]
     /// Get the scheduling queue by index from ksReadyQueues
-    pub fn get_sched_queue(&mut self, index: usize) -> &'static mut tcb_queue_t {
+    pub fn get_sched_queue(&mut self, index: usize) -> &'static mut tcb_queue_t<SchedLink> {
         unsafe {
             #[cfg(feature = "enable_smp")]
             {
@@ -368,10 +569,14 @@ impl tcb_t {
             }
 
             self.tcbState.set_tcbQueued(0);
+            #[cfg(feature = "smp_load_balance")]
+            crate::load_balance::count_ready(self.get_cpu(), dom, false);
 
             if likely(queue.head == 0) {
                 remove_from_bigmap(self.get_cpu(), dom, prio);
             }
+            #[cfg(feature = "sched_trace")]
+            sched_trace_tcb(SchedEventKind::Dequeue, self, 0);
         }
     }
 
@@ -392,24 +597,32 @@ impl tcb_t {
                 convert_to_mut_type_ref::<sched_context_t>(self.tcbSchedContext).refill_ready()
             );
         }
-        let self_ptr = self as *mut tcb_t;
+        // the timeslice length may have changed since the slice was refilled
+        #[cfg(not(feature = "kernel_mcs"))]
+        {
+            let length = self.get_time_slice_length();
+            if self.tcbTimeSlice == 0 || self.tcbTimeSlice > length {
+                self.tcbTimeSlice = length;
+            }
+        }
         if self.tcbState.get_tcbQueued() == 0 {
             let dom = self.domain;
             let prio = self.tcbPriority;
             let idx = ready_queues_index(dom, prio);
             let queue = self.get_sched_queue(idx);
 
-            if queue.head == 0 {
-                queue.head = self_ptr as usize;
+            if queue.empty() {
                 add_to_bitmap(self.get_cpu(), dom, prio);
+            }
+
+            #[cfg(feature = "edf_band")]
+            if is_edf_priority(prio) {
+                queue.edf_insert(self, false);
             } else {
-                let next = queue.tail;
-                // unsafe { (*next).tcbSchedNext = self_ptr as usize };
-                convert_to_mut_type_ref::<tcb_t>(next).tcbSchedNext = self_ptr as usize;
+                queue.append(self);
             }
-            self.tcbSchedPrev = queue.tail;
-            self.tcbSchedNext = 0;
-            queue.tail = self_ptr as usize;
+            #[cfg(not(feature = "edf_band"))]
+            queue.append(self);
             unsafe {
                 #[cfg(feature = "enable_smp")]
                 {
@@ -423,6 +636,10 @@ impl tcb_t {
                 }
             }
             self.tcbState.set_tcbQueued(1);
+            #[cfg(feature = "smp_load_balance")]
+            crate::load_balance::count_ready(self.get_cpu(), dom, true);
+            #[cfg(feature = "sched_trace")]
+            sched_trace_tcb(SchedEventKind::Enqueue, self, 0);
         }
         #[cfg(feature = "enable_smp")]
         self.update_queue();
@@ -493,6 +710,18 @@ impl tcb_t {
         //     debug!("switch_to_this: {:#x}", self.get_ptr());
         // }
         let _ = self.set_vm_root();
+        self.switch_to_this_in_vspace();
+    }
+
+    #[inline]
+    /// Switch to the TCB like `switch_to_this`, once the caller has set up its address space
+    pub(crate) fn switch_to_this_in_vspace(&mut self) {
+        #[cfg(feature = "benchmark_track_utilisation")]
+        benchmark_utilisation_switch(self);
+        #[cfg(feature = "sched_trace")]
+        sched_trace_tcb(SchedEventKind::Switch, self, 0);
+        #[cfg(feature = "have_fpu")]
+        crate::fpu::fpu_switch_to(self);
         self.sched_dequeue();
         set_current_thread(self);
     }
@@ -552,6 +781,10 @@ impl tcb_t {
         // println!("tcb suspend: {:#x}", self.get_ptr());
         set_thread_state(self, ThreadState::ThreadStateInactive);
         self.sched_dequeue();
+        #[cfg(feature = "have_fpu")]
+        crate::fpu::fpu_release(self);
+        #[cfg(feature = "priority_inheritance")]
+        self.pi_release_clients();
         #[cfg(feature = "kernel_mcs")]
         self.release_remove();
         #[cfg(feature = "kernel_mcs")]
@@ -621,6 +854,8 @@ impl tcb_t {
     /// * `can_grant` - If the cap can be granted
     pub fn setup_caller_cap(&mut self, sender: &mut Self, can_grant: bool) {
         set_thread_state(sender, ThreadState::ThreadStateBlockedOnReply);
+        #[cfg(feature = "priority_inheritance")]
+        sender.pi_block_on(self);
         let reply_slot = sender.get_cspace_mut_ref(TCB_REPLY);
         let master_cap = cap::cap_reply_cap(&reply_slot.capability);
 
@@ -646,6 +881,14 @@ impl tcb_t {
     /// Delete the caller cap of the TCB
     pub fn delete_caller_cap(&mut self) {
         let caller_slot = self.get_cspace_mut_ref(TCB_CALLER);
+        // the server no longer holds the reply cap, so it stops inheriting from the caller
+        #[cfg(feature = "priority_inheritance")]
+        if caller_slot.capability.get_tag() == cap_tag::cap_reply_cap {
+            let caller = cap::cap_reply_cap(&caller_slot.capability).get_capTCBPtr() as usize;
+            if convert_to_mut_type_ref::<tcb_t>(caller).tcbPIServer == self.get_ptr() {
+                convert_to_mut_type_ref::<tcb_t>(caller).pi_unblock();
+            }
+        }
         caller_slot.delete_one();
     }
 
@@ -941,7 +1184,12 @@ impl tcb_t {
     /// Set the fault message registers of the TCB to the receiver
     /// # Arguments
     /// * `receiver` - The receiver TCB
-    pub fn set_fault_mrs(&self, receiver: &mut Self) -> usize {
+    /// # Returns
+    /// The number of message registers set, or `UnknownFault` if the fault has no encoding
+    ///
+    /// This used to return the length directly and panic on an unknown fault; callers that only
+    /// pass encodable faults can keep that behaviour with `.expect(..)`.
+    pub fn set_fault_mrs(&self, receiver: &mut Self) -> Result<usize, UnknownFault> {
         match self.tcbFault.get_tag() {
             seL4_Fault_tag::seL4_Fault_CapFault => {
                 receiver.set_mr(CAP_FAULT_IP, self.tcbArch.get_register(ArchReg::FAULT_IP));
@@ -953,15 +1201,16 @@ impl tcb_t {
                     CAP_FAULT_IN_RECV_PHASE,
                     seL4_Fault::seL4_Fault_CapFault(&self.tcbFault).get_inReceivePhase() as usize,
                 );
-                receiver.set_lookup_fault_mrs(CAP_FAULT_LOOKUP_FAILURE_TYPE, &self.tcbLookupFailure)
+                Ok(receiver
+                    .set_lookup_fault_mrs(CAP_FAULT_LOOKUP_FAILURE_TYPE, &self.tcbLookupFailure))
             }
             seL4_Fault_tag::seL4_Fault_UnknownSyscall => {
                 self.copy_syscall_fault_mrs(receiver);
-                receiver.set_mr(
+                Ok(receiver.set_mr(
                     N_SYSCALL_MESSAGE,
                     seL4_Fault::seL4_Fault_UnknownSyscall(&self.tcbFault).get_syscallNumber()
                         as usize,
-                )
+                ))
             }
             seL4_Fault_tag::seL4_Fault_UserException => {
                 self.copy_exeception_fault_mrs(receiver);
@@ -969,10 +1218,10 @@ impl tcb_t {
                     N_EXCEPTON_MESSAGE,
                     seL4_Fault::seL4_Fault_UserException(&self.tcbFault).get_number() as usize,
                 );
-                receiver.set_mr(
+                Ok(receiver.set_mr(
                     N_EXCEPTON_MESSAGE + 1,
                     seL4_Fault::seL4_Fault_UserException(&self.tcbFault).get_code() as usize,
-                )
+                ))
             }
             seL4_Fault_tag::seL4_Fault_VMFault => {
                 receiver.set_mr(VM_FAULT_IP, self.tcbArch.get_register(ArchReg::FAULT_IP));
@@ -984,24 +1233,99 @@ impl tcb_t {
                     VM_FAULT_PREFETCH_FAULT,
                     seL4_Fault::seL4_Fault_VMFault(&self.tcbFault).get_instructionFault() as usize,
                 );
-                receiver.set_mr(
+                Ok(receiver.set_mr(
                     VM_FAULT_FSR,
                     seL4_Fault::seL4_Fault_VMFault(&self.tcbFault).get_FSR() as usize,
-                )
+                ))
             }
-            _ => {
-                panic!("invalid fault")
+            #[cfg(feature = "kernel_mcs")]
+            seL4_Fault_tag::seL4_Fault_Timeout => {
+                let len = receiver.set_mr(
+                    TIMEOUT_DATA,
+                    seL4_Fault::seL4_Fault_Timeout(&self.tcbFault).get_badge() as usize,
+                );
+                match convert_to_option_mut_type_ref::<sched_context_t>(self.tcbSchedContext) {
+                    Some(sc) => Ok(receiver.set_mr(
+                        TIMEOUT_CONSUMED,
+                        sc.sched_context_update_consumed() as usize,
+                    )),
+                    None => Ok(len),
+                }
+            }
+            #[cfg(feature = "hardware_debug_api")]
+            seL4_Fault_tag::seL4_Fault_DebugException => {
+                let fault = seL4_Fault::seL4_Fault_DebugException(&self.tcbFault);
+                let reason = fault.get_exceptionReason() as usize;
+                receiver.set_mr(
+                    DEBUG_EXCEPTION_FAULT_IP,
+                    self.tcbArch.get_register(ArchReg::FAULT_IP),
+                );
+                let mut len = receiver.set_mr(DEBUG_EXCEPTION_EXCEPTION_REASON, reason);
+                if reason != SINGLE_STEP && reason != SOFTWARE_BREAK_REQUEST {
+                    receiver.set_mr(
+                        DEBUG_EXCEPTION_TRIGGER_ADDRESS,
+                        fault.get_breakpointAddress() as usize,
+                    );
+                    len = receiver.set_mr(
+                        DEBUG_EXCEPTION_BREAKPOINT_NUMBER,
+                        fault.get_breakpointNumber() as usize,
+                    );
+                }
+                Ok(len)
             }
+            #[cfg(feature = "arm_hypervisor_support")]
+            seL4_Fault_tag::seL4_Fault_VGICMaintenance => {
+                let fault = seL4_Fault::seL4_Fault_VGICMaintenance(&self.tcbFault);
+                // an invalid index is reported as -1
+                let idx = if fault.get_idxValid() != 0 {
+                    fault.get_idx() as usize
+                } else {
+                    usize::MAX
+                };
+                Ok(receiver.set_mr(VGIC_MAINTENANCE_IDX, idx))
+            }
+            #[cfg(feature = "arm_hypervisor_support")]
+            seL4_Fault_tag::seL4_Fault_VCPUFault => Ok(receiver.set_mr(
+                VCPU_FAULT_HSR,
+                seL4_Fault::seL4_Fault_VCPUFault(&self.tcbFault).get_hsr() as usize,
+            )),
+            #[cfg(feature = "arm_hypervisor_support")]
+            seL4_Fault_tag::seL4_Fault_VPPIEvent => Ok(receiver.set_mr(
+                VPPI_EVENT_IRQ,
+                seL4_Fault::seL4_Fault_VPPIEvent(&self.tcbFault).get_irq() as usize,
+            )),
+            tag => Err(UnknownFault(tag as usize)),
         }
     }
 
     /// Set the thread state
     #[inline]
     pub fn set_state(&mut self, state: ThreadState) {
-        self.tcbState.set_tsType(state as u64);
-        schedule_tcb(self);
+        set_thread_state(self, state);
+    }
+
+    #[cfg(feature = "debug_build")]
+    /// Panic if the TCB may not move from its current state to `to`
+    pub fn check_state_transition(&self, to: ThreadState) {
+        match self.try_get_state() {
+            Ok(from) => assert!(
+                from.can_transition_to(to),
+                "tcb {:#x}: invalid thread state transition {:?} -> {:?}",
+                self.get_ptr(),
+                from,
+                to
+            ),
+            Err(InvalidThreadState(ts_type)) => panic!(
+                "tcb {:#x}: invalid thread state {} before transition to {:?}",
+                self.get_ptr(),
+                ts_type,
+                to
+            ),
+        }
     }
+    #[cfg(not(feature = "debug_build"))]
     pub fn debug_append(&mut self) {}
+    #[cfg(not(feature = "debug_build"))]
     pub fn debug_remove(&mut self) {}
 
     #[inline]
@@ -1018,16 +1342,17 @@ impl tcb_t {
     }
 
     #[inline]
+    /// Insert the TCB in front of `tcb_after`, which must be in a scheduler queue but not at its head
     pub fn queue_insert(&mut self, tcb_after: &mut tcb_t) {
-        let before = tcb_after.tcbSchedPrev;
-        assert!(before != 0);
-        assert!(before != tcb_after.get_ptr());
+        let before = SchedLink::prev(tcb_after).expect("queue_insert at the head of the queue");
+        assert!(before.as_ptr() as usize != tcb_after.get_ptr());
+        let self_link = link_from_ptr(self.get_ptr());
 
-        self.tcbSchedPrev = before;
-        self.tcbSchedNext = tcb_after.get_ptr();
+        SchedLink::set_prev(self, Some(before));
+        SchedLink::set_next(self, link_from_ptr(tcb_after.get_ptr()));
 
-        tcb_after.tcbSchedPrev = self.get_ptr();
-        convert_to_mut_type_ref::<tcb_t>(before).tcbSchedNext = self.get_ptr();
+        SchedLink::set_prev(tcb_after, self_link);
+        SchedLink::set_next(unsafe { &mut *before.as_ptr() }, self_link);
     }
 
     #[inline]
@@ -1039,7 +1364,10 @@ impl tcb_t {
             if queue.head == self.get_ptr() {
                 SET_NODE_STATE_ON_CORE!(self.tcbAffinity, ksReprogram = true);
             }
+            #[cfg(not(feature = "release_heap"))]
             queue.remove(self);
+            #[cfg(feature = "release_heap")]
+            queue.release_heap_remove(self);
             SET_NODE_STATE_ON_CORE!(self.tcbAffinity, ksReleaseQueue = queue);
 
             self.tcbState.set_tcbInReleaseQueue(0);
@@ -1054,6 +1382,17 @@ impl tcb_t {
         let new_time = self.Ready_Time();
         let mut queue = NODE_STATE_ON_CORE!(self.tcbAffinity, ksReleaseQueue);
 
+        #[cfg(feature = "release_heap")]
+        {
+            // the TCB becomes the root exactly when it is released before the current root
+            if queue.empty() || new_time < convert_to_mut_type_ref::<tcb_t>(queue.head).Ready_Time()
+            {
+                SET_NODE_STATE_ON_CORE!(self.tcbAffinity, ksReprogram = true);
+            }
+            queue.release_heap_insert(self);
+            SET_NODE_STATE_ON_CORE!(self.tcbAffinity, ksReleaseQueue = queue);
+        }
+        #[cfg(not(feature = "release_heap"))]
         if queue.empty() || new_time < convert_to_mut_type_ref::<tcb_t>(queue.head).Ready_Time() {
             queue.prepend(self);
             SET_NODE_STATE_ON_CORE!(self.tcbAffinity, ksReleaseQueue = queue);
@@ -1064,11 +1403,14 @@ impl tcb_t {
                 SET_NODE_STATE_ON_CORE!(self.tcbAffinity, ksReleaseQueue = queue);
             } else {
                 let after = find_time_after(convert_to_mut_type_ref::<tcb_t>(queue.head), new_time);
-                self.queue_insert(convert_to_mut_type_ref(after as usize));
+                queue.insert_before(self, unsafe { &mut *after });
+                SET_NODE_STATE_ON_CORE!(self.tcbAffinity, ksReleaseQueue = queue);
             }
         }
 
         self.tcbState.set_tcbInReleaseQueue(1);
+        #[cfg(feature = "sched_trace")]
+        sched_trace_tcb(SchedEventKind::ReleaseEnqueue, self, new_time as usize);
     }
     #[inline]
     #[cfg(feature = "kernel_mcs")]
@@ -1100,6 +1442,13 @@ impl tcb_t {
 /// * `tcb` - The TCB to set
 /// * `state` - The state
 pub fn set_thread_state(tcb: &mut tcb_t, state: ThreadState) {
+    #[cfg(feature = "debug_build")]
+    tcb.check_state_transition(state);
+    // leaving BlockedOnReply, however it happens, ends the inheritance through this TCB
+    #[cfg(feature = "priority_inheritance")]
+    if state != ThreadState::ThreadStateBlockedOnReply {
+        tcb.pi_unblock();
+    }
     tcb.tcbState.set_tsType(state as u64);
     schedule_tcb(tcb);
 }
@@ -1108,11 +1457,10 @@ pub fn set_thread_state(tcb: &mut tcb_t, state: ThreadState) {
 pub fn find_time_after(tcb: &mut tcb_t, new_time: ticks_t) -> *mut tcb_t {
     let mut after = tcb;
     while after.time_after(new_time) {
-        if after.tcbSchedNext != 0 {
-            after = convert_to_mut_type_ref::<tcb_t>(after.tcbSchedNext)
-        } else {
+        match SchedLink::next(after) {
+            Some(next) => after = unsafe { &mut *next.as_ptr() },
             // we do not check the ptr is 0 in time after, but do it here
-            break;
+            None => break,
         }
     }
     return after;
@@ -1122,10 +1470,13 @@ pub fn find_time_after(tcb: &mut tcb_t, new_time: ticks_t) -> *mut tcb_t {
 pub fn tcb_release_dequeue() -> *mut tcb_t {
     use crate::SET_NODE_STATE;
 
-    assert!(NODE_STATE!(ksReleaseQueue).head != 0);
-    assert!(convert_to_mut_type_ref::<tcb_t>(NODE_STATE!(ksReleaseQueue).head).tcbSchedPrev == 0);
-
-    let awakened = convert_to_mut_type_ref::<tcb_t>(NODE_STATE!(ksReleaseQueue).head);
+    let awakened = unsafe {
+        &mut *NODE_STATE!(ksReleaseQueue)
+            .head_tcb()
+            .expect("release queue is empty")
+            .as_ptr()
+    };
+    assert!(SchedLink::prev(awakened).is_none());
     assert!(awakened.get_ptr() != crate::get_currenct_thread().get_ptr());
 
     awakened.release_remove();
@@ -1,73 +1,178 @@
-use sel4_common::utils::convert_to_mut_type_ref;
+use core::marker::PhantomData;
+use core::ptr::NonNull;
 
 use super::tcb::tcb_t;
 
+/// A link to a TCB in an intrusive queue, `None` ends the queue.
+pub type tcb_link_t = Option<NonNull<tcb_t>>;
+
+#[inline]
+/// Convert a raw TCB pointer, 0 meaning none, to a link.
+pub fn link_from_ptr(ptr: usize) -> tcb_link_t {
+    NonNull::new(ptr as *mut tcb_t)
+}
+
+#[inline]
+/// Convert a link to a raw TCB pointer, 0 meaning none.
+pub fn link_to_ptr(link: tcb_link_t) -> usize {
+    link.map_or(0, |tcb| tcb.as_ptr() as usize)
+}
+
+#[inline]
+fn link_to_tcb(tcb: NonNull<tcb_t>) -> &'static mut tcb_t {
+    unsafe { &mut *tcb.as_ptr() }
+}
+
+#[inline]
+fn link_to_ref(tcb: NonNull<tcb_t>) -> &'static tcb_t {
+    unsafe { &*tcb.as_ptr() }
+}
+
+/// The pair of link fields in `tcb_t` that threads a TCB through one kind of queue.
+///
+/// A TCB can be in one queue of each kind at the same time.
+pub trait TcbLink {
+    fn next(tcb: &tcb_t) -> tcb_link_t;
+    fn prev(tcb: &tcb_t) -> tcb_link_t;
+    fn set_next(tcb: &mut tcb_t, next: tcb_link_t);
+    fn set_prev(tcb: &mut tcb_t, prev: tcb_link_t);
+}
+
+/// The `tcbSchedNext`/`tcbSchedPrev` links, used by the ready and release queues.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct SchedLink;
+
+/// The `tcbEPNext`/`tcbEPPrev` links, used by the endpoint and notification queues.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct EPLink;
+
+impl TcbLink for SchedLink {
+    #[inline]
+    fn next(tcb: &tcb_t) -> tcb_link_t {
+        link_from_ptr(tcb.tcbSchedNext)
+    }
+    #[inline]
+    fn prev(tcb: &tcb_t) -> tcb_link_t {
+        link_from_ptr(tcb.tcbSchedPrev)
+    }
+    #[inline]
+    fn set_next(tcb: &mut tcb_t, next: tcb_link_t) {
+        tcb.tcbSchedNext = link_to_ptr(next);
+    }
+    #[inline]
+    fn set_prev(tcb: &mut tcb_t, prev: tcb_link_t) {
+        tcb.tcbSchedPrev = link_to_ptr(prev);
+    }
+}
+
+impl TcbLink for EPLink {
+    #[inline]
+    fn next(tcb: &tcb_t) -> tcb_link_t {
+        link_from_ptr(tcb.tcbEPNext)
+    }
+    #[inline]
+    fn prev(tcb: &tcb_t) -> tcb_link_t {
+        link_from_ptr(tcb.tcbEPPrev)
+    }
+    #[inline]
+    fn set_next(tcb: &mut tcb_t, next: tcb_link_t) {
+        tcb.tcbEPNext = link_to_ptr(next);
+    }
+    #[inline]
+    fn set_prev(tcb: &mut tcb_t, prev: tcb_link_t) {
+        tcb.tcbEPPrev = link_to_ptr(prev);
+    }
+}
+
 #[repr(C)]
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
 /// Structure for the tcb queue
-pub struct tcb_queue_t {
+///
+/// `head` and `tail` stay raw pointers so that the layout matches the C kernel. The link kind
+/// `L` is part of the type, so a queue can only be walked and linked through its own fields,
+/// e.g. the ready queues are `tcb_queue_t<SchedLink>`.
+pub struct tcb_queue_t<L: TcbLink> {
     /// The head of the queue
     pub head: usize,
     /// The tail of the queue
     pub tail: usize,
+    _link: PhantomData<L>,
+}
+
+/// Iterator over the TCBs of a queue, from head to tail.
+pub struct TcbQueueIter<L: TcbLink> {
+    next: tcb_link_t,
+    _link: PhantomData<L>,
 }
 
-impl tcb_queue_t {
+impl<L: TcbLink> Iterator for TcbQueueIter<L> {
+    type Item = &'static tcb_t;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let tcb = link_to_ref(self.next?);
+        self.next = L::next(tcb);
+        Some(tcb)
+    }
+}
+
+impl tcb_queue_t<EPLink> {
     /// Append a tcb to the queue
-    #[cfg(not(feature = "kernel_mcs"))]
+    #[cfg(not(any(feature = "kernel_mcs", feature = "ep_priority_queue")))]
     pub fn ep_append(&mut self, tcb: &mut tcb_t) {
-        if self.head == 0 {
-            self.head = tcb.get_ptr();
-        } else {
-            convert_to_mut_type_ref::<tcb_t>(self.tail).tcbEPNext = tcb.get_ptr();
-        }
-
-        tcb.tcbEPPrev = self.tail;
-        tcb.tcbEPNext = 0;
-        self.tail = tcb.get_ptr();
+        self.push_back(tcb);
     }
-    #[cfg(feature = "kernel_mcs")]
+    /// Insert a tcb after the last tcb of the same or higher priority
+    #[cfg(any(feature = "kernel_mcs", feature = "ep_priority_queue"))]
     pub fn ep_append(&mut self, tcb: &mut tcb_t) {
-        use core::intrinsics::{likely, unlikely};
-
-        let mut before_ptr: usize = self.tail;
-        let mut after_ptr: usize = 0;
+        let mut before = self.tail_tcb();
+        let mut after: tcb_link_t = None;
 
-        while unlikely(
-            before_ptr != 0
-                && tcb.tcbPriority > convert_to_mut_type_ref::<tcb_t>(before_ptr).tcbPriority,
-        ) {
-            after_ptr = before_ptr;
-            before_ptr = convert_to_mut_type_ref::<tcb_t>(after_ptr).tcbEPPrev;
-        }
-        if unlikely(before_ptr == 0) {
-            self.head = tcb.get_ptr();
-        } else {
-            convert_to_mut_type_ref::<tcb_t>(before_ptr).tcbEPNext = tcb.get_ptr()
+        while let Some(before_tcb) = before {
+            let before_tcb = link_to_ref(before_tcb);
+            if tcb.tcbPriority <= before_tcb.tcbPriority {
+                break;
+            }
+            after = before;
+            before = EPLink::prev(before_tcb);
         }
-
-        if likely(after_ptr == 0) {
-            self.tail = tcb.get_ptr();
-        } else {
-            convert_to_mut_type_ref::<tcb_t>(after_ptr).tcbEPPrev = tcb.get_ptr();
+        match after {
+            None => self.push_back(tcb),
+            Some(after_tcb) => self.insert_before(tcb, link_to_tcb(after_tcb)),
         }
-
-        tcb.tcbEPNext = after_ptr;
-        tcb.tcbEPPrev = before_ptr;
     }
 
     /// Dequeue a tcb from the queue
     pub fn ep_dequeue(&mut self, tcb: &mut tcb_t) {
-        if tcb.tcbEPPrev != 0 {
-            convert_to_mut_type_ref::<tcb_t>(tcb.tcbEPPrev).tcbEPNext = tcb.tcbEPNext;
-        } else {
-            self.head = tcb.tcbEPNext;
-        }
+        self.unlink(tcb);
+    }
+}
 
-        if tcb.tcbEPNext != 0 {
-            convert_to_mut_type_ref::<tcb_t>(tcb.tcbEPNext).tcbEPPrev = tcb.tcbEPPrev;
-        } else {
-            self.tail = tcb.tcbEPPrev;
+impl tcb_queue_t<SchedLink> {
+    #[inline]
+    /// Add a tcb to the head of the ready or release queue
+    pub fn prepend(&mut self, tcb: &mut tcb_t) {
+        self.push_front(tcb);
+    }
+    #[inline]
+    /// Add a tcb to the tail of the ready or release queue
+    pub fn append(&mut self, tcb: &mut tcb_t) {
+        self.push_back(tcb);
+    }
+    #[inline]
+    /// Remove a tcb from the ready or release queue
+    pub fn remove(&mut self, tcb: &mut tcb_t) {
+        self.unlink(tcb);
+    }
+}
+
+impl<L: TcbLink> tcb_queue_t<L> {
+    #[inline]
+    /// Create an empty queue
+    pub const fn new() -> Self {
+        Self {
+            head: 0,
+            tail: 0,
+            _link: PhantomData,
         }
     }
 
@@ -76,50 +181,94 @@ impl tcb_queue_t {
     pub fn empty(&self) -> bool {
         return self.head == 0;
     }
+
     #[inline]
-    pub fn prepend(&mut self, tcb: &mut tcb_t) {
-        if self.empty() {
-            self.tail = tcb.get_ptr();
-        } else {
-            tcb.tcbSchedNext = self.head;
-            convert_to_mut_type_ref::<tcb_t>(self.head).tcbSchedPrev = tcb.get_ptr();
+    /// Get the head of the queue
+    pub fn head_tcb(&self) -> tcb_link_t {
+        link_from_ptr(self.head)
+    }
+    #[inline]
+    /// Get the tail of the queue
+    pub fn tail_tcb(&self) -> tcb_link_t {
+        link_from_ptr(self.tail)
+    }
+
+    #[inline]
+    /// Iterate over the queue from head to tail
+    pub fn iter(&self) -> TcbQueueIter<L> {
+        TcbQueueIter {
+            next: self.head_tcb(),
+            _link: PhantomData,
         }
+    }
+
+    #[allow(clippy::len_without_is_empty)]
+    /// Count the tcbs in the queue
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    /// Check if the tcb is in the queue
+    pub fn contains(&self, tcb: &tcb_t) -> bool {
+        self.iter().any(|queued| queued.get_ptr() == tcb.get_ptr())
+    }
+    /// Add a tcb to the head of the queue
+    pub fn push_front(&mut self, tcb: &mut tcb_t) {
+        let tcb_link = link_from_ptr(tcb.get_ptr());
+        match self.head_tcb() {
+            None => self.tail = tcb.get_ptr(),
+            Some(head) => L::set_prev(link_to_tcb(head), tcb_link),
+        }
+        L::set_prev(tcb, None);
+        L::set_next(tcb, self.head_tcb());
         self.head = tcb.get_ptr();
     }
-    #[inline]
-    pub fn append(&mut self, tcb: &mut tcb_t) {
-        if self.empty() {
-            self.head = tcb.get_ptr();
-        } else {
-            tcb.tcbSchedPrev = self.tail;
-            convert_to_mut_type_ref::<tcb_t>(self.tail).tcbSchedNext = tcb.get_ptr();
+
+    /// Add a tcb to the tail of the queue
+    pub fn push_back(&mut self, tcb: &mut tcb_t) {
+        let tcb_link = link_from_ptr(tcb.get_ptr());
+        match self.tail_tcb() {
+            None => self.head = tcb.get_ptr(),
+            Some(tail) => L::set_next(link_to_tcb(tail), tcb_link),
         }
+        L::set_prev(tcb, self.tail_tcb());
+        L::set_next(tcb, None);
         self.tail = tcb.get_ptr();
     }
-    #[inline]
-    pub fn remove(&mut self, tcb: &mut tcb_t) {
-        let before = tcb.tcbSchedPrev;
-        let after = tcb.tcbSchedNext;
-        if self.head == tcb.get_ptr() && self.tail == tcb.get_ptr() {
-            self.head = 0;
-            self.tail = 0;
-        } else {
-            if self.head == tcb.get_ptr() {
-                convert_to_mut_type_ref::<tcb_t>(after).tcbSchedPrev = 0;
-                tcb.tcbSchedNext = 0;
-                self.head = after;
-            } else {
-                if self.tail == tcb.get_ptr() {
-                    convert_to_mut_type_ref::<tcb_t>(before).tcbSchedNext = 0;
-                    tcb.tcbSchedPrev = 0;
-                    self.tail = before;
-                } else {
-                    convert_to_mut_type_ref::<tcb_t>(before).tcbSchedNext = after;
-                    convert_to_mut_type_ref::<tcb_t>(after).tcbSchedPrev = before;
-                    tcb.tcbSchedPrev = 0;
-                    tcb.tcbSchedNext = 0;
-                }
-            }
+
+    /// Insert a tcb in front of `after`, which must be in the queue
+    pub fn insert_before(&mut self, tcb: &mut tcb_t, after: &mut tcb_t) {
+        let tcb_link = link_from_ptr(tcb.get_ptr());
+        let before = L::prev(after);
+        match before {
+            None => self.head = tcb.get_ptr(),
+            Some(before_tcb) => L::set_next(link_to_tcb(before_tcb), tcb_link),
         }
+        L::set_prev(tcb, before);
+        L::set_next(tcb, link_from_ptr(after.get_ptr()));
+        L::set_prev(after, tcb_link);
+    }
+
+    /// Remove a tcb from the queue and clear its links
+    pub fn unlink(&mut self, tcb: &mut tcb_t) {
+        let before = L::prev(tcb);
+        let after = L::next(tcb);
+        match before {
+            None => self.head = link_to_ptr(after),
+            Some(before_tcb) => L::set_next(link_to_tcb(before_tcb), after),
+        }
+        match after {
+            None => self.tail = link_to_ptr(before),
+            Some(after_tcb) => L::set_prev(link_to_tcb(after_tcb), before),
+        }
+        L::set_prev(tcb, None);
+        L::set_next(tcb, None);
+    }
+
+    /// Remove and return the head of the queue
+    pub fn pop_front(&mut self) -> Option<&'static mut tcb_t> {
+        let head = link_to_tcb(self.head_tcb()?);
+        self.unlink(head);
+        Some(head)
     }
 }
@@ -1,4 +1,4 @@
-#[derive(PartialEq, PartialOrd, Debug)]
+#[derive(PartialEq, PartialOrd, Debug, Clone, Copy)]
 /// The state of a thread
 pub enum ThreadState {
     ThreadStateInactive = 0,
@@ -12,14 +12,73 @@ pub enum ThreadState {
     ThreadStateExited = 8,
 }
 
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+/// A `tsType` value that is not a `ThreadState`
+pub struct InvalidThreadState(pub u64);
+
+impl TryFrom<u64> for ThreadState {
+    type Error = InvalidThreadState;
+
+    fn try_from(ts_type: u64) -> Result<Self, Self::Error> {
+        match ts_type {
+            0 => Ok(ThreadState::ThreadStateInactive),
+            1 => Ok(ThreadState::ThreadStateRunning),
+            2 => Ok(ThreadState::ThreadStateRestart),
+            3 => Ok(ThreadState::ThreadStateBlockedOnReceive),
+            4 => Ok(ThreadState::ThreadStateBlockedOnSend),
+            5 => Ok(ThreadState::ThreadStateBlockedOnReply),
+            6 => Ok(ThreadState::ThreadStateBlockedOnNotification),
+            7 => Ok(ThreadState::ThreadStateIdleThreadState),
+            8 => Ok(ThreadState::ThreadStateExited),
+            _ => Err(InvalidThreadState(ts_type)),
+        }
+    }
+}
+
+impl ThreadState {
+    /// Check if a thread in this state may be set to `to`.
+    ///
+    /// - Only a runnable thread can block, except that a caller blocked on send moves on to
+    ///   wait for the reply once its message is received.
+    /// - The idle thread state is only entered once, by an inactive TCB, and never left.
+    /// - Every other transition, e.g. suspending or restarting a thread, is allowed.
+    pub fn can_transition_to(self, to: ThreadState) -> bool {
+        use ThreadState::*;
+        if self == to {
+            return true;
+        }
+        match (self, to) {
+            (ThreadStateIdleThreadState, _) => false,
+            (from, ThreadStateIdleThreadState) => from == ThreadStateInactive,
+            (ThreadStateBlockedOnSend, ThreadStateBlockedOnReply) => true,
+            (
+                from,
+                ThreadStateBlockedOnReceive
+                | ThreadStateBlockedOnSend
+                | ThreadStateBlockedOnReply
+                | ThreadStateBlockedOnNotification,
+            ) => from == ThreadStateRunning || from == ThreadStateRestart,
+            _ => true,
+        }
+    }
+}
+
 use sel4_common::structures_gen::thread_state;
 
 pub trait thread_state_func {
     fn get_state(&self) -> ThreadState;
+    fn try_get_state(&self) -> Result<ThreadState, InvalidThreadState>;
 }
 impl thread_state_func for thread_state {
-    /// Get the state of the thread
+    /// Get the state of the thread, panicking if `tsType` is corrupted
     fn get_state(&self) -> ThreadState {
-        unsafe { core::mem::transmute::<u8, ThreadState>(self.get_tsType() as u8) }
+        match self.try_get_state() {
+            Ok(state) => state,
+            Err(InvalidThreadState(ts_type)) => panic!("invalid thread state {}", ts_type),
+        }
+    }
+    /// Get the state of the thread
+    fn try_get_state(&self) -> Result<ThreadState, InvalidThreadState> {
+        ThreadState::try_from(self.get_tsType() as u64)
     }
 }
@@ -0,0 +1,89 @@
+//! Tickless scheduling for the non-MCS kernel, enabled by the `tickless` feature.
+//!
+//! The timer interrupt still calls `timer_tick`, but the timer is programmed with one-shot
+//! deadlines instead of a periodic tick. `ksTickStart` is the time up to which this core has
+//! charged whole ticks of `ksTickLength` timer ticks to the timeslice of its current thread and
+//! to the domain time. `timer_tick` and `schedule` charge the ticks elapsed since then, and at
+//! the end of `schedule` the timer is programmed for the end of the timeslice of the current
+//! thread if another thread shares its priority, or for the domain boundary if there are
+//! several domains, whichever comes first. Otherwise the timer is stopped, e.g. while the idle
+//! thread runs.
+
+use sel4_common::platform::time_def::ticks_t;
+use sel4_common::sel4_config::CONFIG_NUM_DOMAINS;
+#[cfg(feature = "enable_smp")]
+use sel4_common::utils::cpu_id;
+
+use crate::arch::{timer, Timer_func};
+use crate::scheduler::consume_ticks;
+use crate::{
+    get_currenct_thread, get_idle_thread, ksDomainTime, ready_queues_index, tcb_t, NODE_STATE,
+    SET_NODE_STATE,
+};
+
+/// Length of a tick in timer ticks, set by `tickless_init`.
+pub static mut ksTickLength: ticks_t = 0;
+
+/// Start tickless scheduling on this core, with ticks of `tick_length` timer ticks.
+///
+/// Every core calls it at boot, before its first `schedule`.
+pub fn tickless_init(tick_length: ticks_t) {
+    assert!(tick_length > 0);
+    unsafe { ksTickLength = tick_length };
+    SET_NODE_STATE!(ksTickStart = timer.get_current_time());
+}
+
+/// Charge the whole ticks elapsed on this core since they were last charged.
+pub fn tickless_account() {
+    let length = unsafe { ksTickLength };
+    let start = NODE_STATE!(ksTickStart);
+    let ticks = (timer.get_current_time() - start) / length;
+    if ticks == 0 {
+        return;
+    }
+    SET_NODE_STATE!(ksTickStart = start + ticks * length);
+    consume_ticks(ticks as usize);
+}
+
+/// Program the timer for the next tick that can change the scheduling decision of this core,
+/// or stop it if there is none.
+pub fn tickless_reprogram() {
+    let current = get_currenct_thread();
+    let mut ticks = None;
+    if current.get_ptr() != get_idle_thread().get_ptr() && shares_priority(current) {
+        ticks = Some(current.tcbTimeSlice);
+    }
+    if consumes_domain_time() {
+        let domain_time = unsafe { ksDomainTime };
+        ticks = Some(ticks.map_or(domain_time, |ticks: usize| ticks.min(domain_time)));
+    }
+    let deadline = match ticks {
+        Some(ticks) => NODE_STATE!(ksTickStart) + ticks as ticks_t * unsafe { ksTickLength },
+        None => ticks_t::MAX,
+    };
+    timer.set_deadline(deadline);
+}
+
+/// Whether another thread is ready at the domain and priority of `tcb` on this core.
+fn shares_priority(tcb: &tcb_t) -> bool {
+    let index = ready_queues_index(tcb.domain, tcb.tcbPriority);
+    unsafe {
+        #[cfg(feature = "enable_smp")]
+        {
+            crate::ksSMP[cpu_id()].ksReadyQueues[index].head != 0
+        }
+        #[cfg(not(feature = "enable_smp"))]
+        {
+            crate::ksReadyQueues[index].head != 0
+        }
+    }
+}
+
+/// Whether this core consumes the domain time, which only the boot core does.
+fn consumes_domain_time() -> bool {
+    #[cfg(feature = "enable_smp")]
+    if cpu_id() != 0 {
+        return false;
+    }
+    CONFIG_NUM_DOMAINS > 1
+}
@@ -0,0 +1,513 @@
+//! MCS refill and release queue tests, run against the `host_sim` backend.
+#![cfg(all(feature = "host_sim", feature = "kernel_mcs"))]
+
+use sel4_task::host_sim::Sim;
+use sel4_task::sched_context::{refill_budget_check, sched_context_t, MIN_REFILLS};
+use sel4_task::{
+    check_budget, get_currenct_thread, get_idle_thread, reschedule_required, schedule,
+    set_thread_state, tcb_t, ThreadState, NODE_STATE, SET_NODE_STATE,
+};
+
+fn new_sc(sim: &mut Sim, budget: usize, period: usize) -> &'static mut sched_context_t {
+    let sc = sim.arena.new_sched_context(8);
+    sc.refill_new(8, budget, period);
+    sc.scSporadic = true;
+    sc
+}
+
+fn runnable(sim: &mut Sim, prio: usize) -> &'static mut tcb_t {
+    let tcb = sim.new_thread(prio, 0);
+    set_thread_state(tcb, ThreadState::ThreadStateRunning);
+    tcb
+}
+
+fn head(sc: &sched_context_t) -> (usize, usize) {
+    unsafe { ((*sc.refill_head()).rTime, (*sc.refill_head()).rAmount) }
+}
+
+fn tail(sc: &sched_context_t) -> (usize, usize) {
+    unsafe { ((*sc.refill_tail()).rTime, (*sc.refill_tail()).rAmount) }
+}
+
+#[test]
+fn refill_new_creates_single_refill() {
+    let mut sim = Sim::new();
+    SET_NODE_STATE!(ksCurTime = 40);
+    let sc = new_sc(&mut sim, 100, 1000);
+    assert_eq!(sc.refill_size(), 1);
+    assert_eq!(head(sc), (40, 100));
+    assert_eq!(sc.refill_sum(), 100);
+    assert!(!sc.is_round_robin());
+}
+
+#[test]
+fn round_robin_refill_has_empty_tail() {
+    let mut sim = Sim::new();
+    let sc = sim.arena.new_sched_context(MIN_REFILLS);
+    sc.refill_new(MIN_REFILLS, 100, 0);
+    assert!(sc.is_round_robin());
+    assert_eq!(sc.refill_size(), 2);
+    assert_eq!(tail(sc).1, 0);
+    assert_eq!(sc.refill_sum(), 100);
+}
+
+#[test]
+fn refill_budget_check_moves_usage_to_next_period() {
+    let mut sim = Sim::new();
+    let sc = new_sc(&mut sim, 100, 1000);
+    SET_NODE_STATE!(ksCurSC = sc.get_ptr());
+
+    refill_budget_check(30);
+    assert_eq!(head(sc), (30, 70));
+    assert_eq!(tail(sc), (1000, 30));
+    assert_eq!(sc.refill_sum(), 100);
+}
+
+#[test]
+fn refill_unblock_check_merges_overlapping_refills() {
+    let mut sim = Sim::new();
+    let sc = new_sc(&mut sim, 60, 1000);
+    sc.refill_add_tail(50, 40);
+
+    SET_NODE_STATE!(ksCurTime = 200);
+    sc.refill_unblock_check();
+    assert_eq!(sc.refill_size(), 1);
+    assert_eq!(head(sc), (200, 100));
+    assert!(NODE_STATE!(ksReprogram));
+}
+
+#[test]
+fn awaken_releases_thread_when_its_refill_is_ready() {
+    let mut sim = Sim::new();
+    let tcb = runnable(&mut sim, 10);
+    let sc = new_sc(&mut sim, 100, 1000);
+    unsafe { (*sc.refill_head()).rTime = 500 };
+
+    sc.sched_context_bind_tcb(tcb);
+    assert_eq!(tcb.tcbState.get_tcbInReleaseQueue(), 1);
+    assert_eq!(NODE_STATE!(ksReleaseQueue).head, tcb.get_ptr());
+
+    schedule();
+    assert_eq!(get_currenct_thread().get_ptr(), get_idle_thread().get_ptr());
+
+    SET_NODE_STATE!(ksCurTime = 500);
+    schedule();
+    assert_eq!(get_currenct_thread().get_ptr(), tcb.get_ptr());
+    assert_eq!(NODE_STATE!(ksCurSC), sc.get_ptr());
+    assert!(NODE_STATE!(ksReleaseQueue).empty());
+    assert!(sim_deadline() <= 600);
+}
+
+#[test]
+fn exhausted_budget_postpones_current_thread() {
+    let mut sim = Sim::new();
+    let tcb = runnable(&mut sim, 10);
+    let sc = new_sc(&mut sim, 100, 1000);
+
+    sc.sched_context_bind_tcb(tcb);
+    reschedule_required();
+    schedule();
+    assert_eq!(get_currenct_thread().get_ptr(), tcb.get_ptr());
+
+    SET_NODE_STATE!(ksCurTime = 100);
+    SET_NODE_STATE!(ksConsumed = 100);
+    assert!(!check_budget());
+    assert_eq!(sc.scConsumed, 100);
+    assert_eq!(head(sc), (1000, 100));
+    assert_eq!(tcb.tcbState.get_tcbInReleaseQueue(), 1);
+
+    schedule();
+    assert_eq!(get_currenct_thread().get_ptr(), get_idle_thread().get_ptr());
+}
+
+fn sim_deadline() -> usize {
+    sel4_task::host_sim::arch::timer.deadline()
+}
+
+#[test]
+fn set_priority_reorders_endpoint_queue() {
+    use sel4_task::host_sim::endpoint_queue;
+
+    let mut sim = Sim::new();
+    let ep = sim.arena.new_endpoint();
+    let a = runnable(&mut sim, 100);
+    let b = runnable(&mut sim, 50);
+    for tcb in [&mut *a, &mut *b] {
+        sim.block_on_endpoint(tcb, ep, ThreadState::ThreadStateBlockedOnReceive);
+    }
+    b.set_priority(150);
+    let order: Vec<usize> = endpoint_queue(ep).iter().map(|t| t.get_ptr()).collect();
+    assert_eq!(order, [b.get_ptr(), a.get_ptr()]);
+    assert_eq!(b.tcbPriority, 150);
+}
+
+#[test]
+fn timeout_fault_messages() {
+    use sel4_common::structures_gen::seL4_Fault_Timeout;
+    use sel4_task::host_sim::arch::ArchReg;
+    use sel4_task::{TIMEOUT_CONSUMED, TIMEOUT_DATA};
+
+    let mut sim = Sim::new();
+    let sender = runnable(&mut sim, 10);
+    let receiver = runnable(&mut sim, 10);
+    let mr = |tcb: &tcb_t, i| tcb.tcbArch.get_register(ArchReg::Msg(i));
+
+    // without an SC only the badge is sent
+    sender.tcbFault = seL4_Fault_Timeout::new(0xbad).unsplay();
+    assert_eq!(sender.set_fault_mrs(receiver), Ok(1));
+    assert_eq!(mr(receiver, TIMEOUT_DATA), 0xbad);
+
+    // with an SC the consumed time is sent and reset
+    let sc = new_sc(&mut sim, 100, 1000);
+    sc.sched_context_bind_tcb(sender);
+    sc.scConsumed = 42;
+    assert_eq!(sender.set_fault_mrs(receiver), Ok(2));
+    assert_eq!(mr(receiver, TIMEOUT_DATA), 0xbad);
+    assert_eq!(mr(receiver, TIMEOUT_CONSUMED), 42);
+    assert_eq!(sc.scConsumed, 0);
+}
+
+#[cfg(feature = "edf_band")]
+#[test]
+fn edf_band_orders_by_deadline() {
+    use sel4_task::host_sim::ready_queue;
+    use sel4_task::{possible_switch_to, set_edf_priority, SCHEDULER_ACTION_RESUME_CURRENT_THREAD};
+
+    let mut sim = Sim::new();
+    set_edf_priority(Some(10));
+    let late = runnable(&mut sim, 10);
+    let early = runnable(&mut sim, 10);
+    let middle = runnable(&mut sim, 10);
+    for (tcb, period) in [(&mut *late, 1000), (&mut *early, 300), (&mut *middle, 600)] {
+        new_sc(&mut sim, 100, period).sched_context_bind_tcb(tcb);
+    }
+    let order: Vec<usize> = ready_queue(0, 10).iter().map(|t| t.get_ptr()).collect();
+    assert_eq!(order, [early.get_ptr(), middle.get_ptr(), late.get_ptr()]);
+
+    schedule();
+    assert_eq!(get_currenct_thread().get_ptr(), early.get_ptr());
+
+    // a later deadline does not preempt the current thread
+    let lazy = sim.new_thread(10, 0);
+    new_sc(&mut sim, 100, 2000).sched_context_bind_tcb(lazy);
+    set_thread_state(lazy, ThreadState::ThreadStateRunning);
+    possible_switch_to(lazy);
+    assert_eq!(
+        NODE_STATE!(ksSchedulerAction),
+        SCHEDULER_ACTION_RESUME_CURRENT_THREAD
+    );
+    assert_eq!(ready_queue(0, 10).tail, lazy.get_ptr());
+
+    // an earlier one does
+    let urgent = sim.new_thread(10, 0);
+    new_sc(&mut sim, 100, 200).sched_context_bind_tcb(urgent);
+    set_thread_state(urgent, ThreadState::ThreadStateRunning);
+    possible_switch_to(urgent);
+    assert_eq!(NODE_STATE!(ksSchedulerAction), urgent.get_ptr());
+    schedule();
+    assert_eq!(get_currenct_thread().get_ptr(), urgent.get_ptr());
+    assert_eq!(ready_queue(0, 10).head, early.get_ptr());
+}
+
+#[cfg(feature = "sc_admission")]
+#[test]
+fn admission_rejects_overloading_a_core() {
+    use sel4_common::sel4_config::CONFIG_NUM_DOMAINS;
+    use sel4_task::sched_context::ConfigureError;
+    use sel4_task::{
+        core_utilisation, remaining_capacity, set_domain_utilisation_cap, AdmissionError,
+    };
+
+    let mut sim = Sim::new();
+    let a = sim.arena.new_sched_context(8);
+    assert_eq!(a.sched_context_configure(0, 0, 600, 1000, 8), Ok(()));
+    assert_eq!(core_utilisation(0), 600_000);
+    assert_eq!(a.scAdmittedUtilisation, 600_000);
+
+    // a rejected configuration leaves the SC unconfigured
+    let b = sim.arena.new_sched_context(8);
+    assert_eq!(
+        b.sched_context_configure(0, 0, 500, 1000, 8),
+        Err(ConfigureError::Admission(AdmissionError::CoreOverload))
+    );
+    assert!(!b.sc_active());
+    assert_eq!(b.sched_context_configure(0, 0, 400, 1000, 8), Ok(()));
+    assert_eq!(remaining_capacity(0, 0), 0);
+
+    // reconfiguring an admitted SC replaces its utilisation
+    assert_eq!(a.sched_context_configure(0, 0, 300, 1000, 8), Ok(()));
+    assert_eq!(core_utilisation(0), 700_000);
+
+    // round robin SCs are free
+    let rr = sim.arena.new_sched_context(8);
+    assert_eq!(rr.sched_context_configure(0, 0, 1000, 1000, 8), Ok(()));
+    assert!(rr.is_round_robin());
+    assert_eq!(core_utilisation(0), 700_000);
+
+    if CONFIG_NUM_DOMAINS > 1 {
+        assert_eq!(set_domain_utilisation_cap(1, 200_000), Ok(()));
+        let c = sim.arena.new_sched_context(8);
+        assert_eq!(
+            c.sched_context_configure(0, 1, 250, 1000, 8),
+            Err(ConfigureError::Admission(AdmissionError::DomainOverload))
+        );
+    }
+
+    // unbinding the TCB gives back exactly the admitted utilisation
+    let tcb = runnable(&mut sim, 10);
+    a.sched_context_bind_tcb(tcb);
+    a.sched_context_unbind_tcb(tcb);
+    assert_eq!(core_utilisation(0), 400_000);
+    assert!(!a.scAdmitted);
+
+    // and so does deleting the SC
+    b.sched_context_finalise();
+    assert_eq!(core_utilisation(0), 0);
+    assert!(!b.sc_active());
+}
+
+#[cfg(feature = "debug_build")]
+#[test]
+#[should_panic(expected = "fails the refill audit: refills are out of order or overlap")]
+fn audit_rejects_overlapping_refills() {
+    let mut sim = Sim::new();
+    let sc = new_sc(&mut sim, 100, 1000);
+    SET_NODE_STATE!(ksCurSC = sc.get_ptr());
+    refill_budget_check(30);
+    sc.audit(100);
+
+    // the tail now starts before the head ends
+    unsafe { (*sc.refill_tail()).rTime = 90 };
+    sc.audit(100);
+}
+
+#[test]
+fn yield_to_donates_time_to_equal_or_higher_priority() {
+    use sel4_task::host_sim::ready_queue;
+    use sel4_task::sched_context::YieldToError;
+    use sel4_task::SCHEDULER_ACTION_RESUME_CURRENT_THREAD;
+
+    let mut sim = Sim::new();
+    let peer = runnable(&mut sim, 10);
+    let peer_sc = new_sc(&mut sim, 100, 1000);
+    peer_sc.sched_context_bind_tcb(peer);
+    let low = runnable(&mut sim, 5);
+    let low_sc = new_sc(&mut sim, 100, 1000);
+    low_sc.sched_context_bind_tcb(low);
+    let low_peer = runnable(&mut sim, 5);
+    new_sc(&mut sim, 100, 1000).sched_context_bind_tcb(low_peer);
+    let caller = runnable(&mut sim, 10);
+    let caller_sc = new_sc(&mut sim, 100, 1000);
+    caller_sc.sched_context_bind_tcb(caller);
+    let high = sim.new_thread(20, 0);
+    let high_sc = new_sc(&mut sim, 100, 1000);
+    high_sc.sched_context_bind_tcb(high);
+    schedule();
+    assert_eq!(get_currenct_thread().get_ptr(), caller.get_ptr());
+
+    assert_eq!(
+        caller_sc.sched_context_yield_to(caller),
+        Err(YieldToError::YieldToSelf)
+    );
+    assert_eq!(
+        high_sc.sched_context_yield_to(caller),
+        Err(YieldToError::PriorityAboveMcp)
+    );
+
+    // a lower priority returns the consumed time right away, and the target goes to the
+    // head of its queue
+    low_sc.scConsumed = 50;
+    assert_eq!(low_sc.sched_context_yield_to(caller), Ok(()));
+    assert_eq!(low_sc.scConsumed, 0);
+    let queue = ready_queue(0, 5);
+    assert_eq!(
+        (queue.head, queue.tail),
+        (low.get_ptr(), low_peer.get_ptr())
+    );
+    assert_eq!(caller.tcbYieldTo, 0);
+    assert_eq!(
+        NODE_STATE!(ksSchedulerAction),
+        SCHEDULER_ACTION_RESUME_CURRENT_THREAD
+    );
+
+    // an equal priority runs first while the caller waits
+    assert_eq!(peer_sc.sched_context_yield_to(caller), Ok(()));
+    assert_eq!(caller.tcbYieldTo, peer_sc.get_ptr());
+    assert_eq!(peer_sc.scYieldFrom, caller.get_ptr());
+    schedule();
+    assert_eq!(get_currenct_thread().get_ptr(), peer.get_ptr());
+    assert_eq!(ready_queue(0, 10).head, caller.get_ptr());
+
+    // unbinding the target cancels the yield
+    peer_sc.sched_context_unbind_tcb(peer);
+    assert_eq!(caller.tcbYieldTo, 0);
+    assert_eq!(peer_sc.scYieldFrom, 0);
+}
+
+#[cfg(feature = "release_heap")]
+#[test]
+fn release_heap_releases_in_ready_time_order() {
+    use sel4_task::tcb_release_dequeue;
+
+    let mut sim = Sim::new();
+    let times = [700, 300, 900, 300, 500, 1100, 200, 800];
+    let mut tcbs: Vec<&'static mut tcb_t> = times
+        .iter()
+        .map(|&time| {
+            let tcb = runnable(&mut sim, 10);
+            let sc = new_sc(&mut sim, 100, 2000);
+            unsafe { (*sc.refill_head()).rTime = time };
+            sc.sched_context_bind_tcb(tcb);
+            tcb
+        })
+        .collect();
+    assert_eq!(NODE_STATE!(ksReleaseQueue).head, tcbs[6].get_ptr());
+
+    // removing a TCB in the middle of the heap keeps the others
+    tcbs[4].release_remove();
+    assert_eq!(tcbs[4].tcbState.get_tcbInReleaseQueue(), 0);
+
+    let mut released = Vec::new();
+    while !NODE_STATE!(ksReleaseQueue).empty() {
+        released.push(unsafe { (*tcb_release_dequeue()).Ready_Time() });
+    }
+    assert_eq!(released, [200, 300, 300, 700, 800, 900, 1100]);
+}
+
+#[test]
+fn set_affinity_follows_the_sched_context() {
+    use sel4_task::AffinityError;
+
+    let mut sim = Sim::new();
+    let tcb = runnable(&mut sim, 10);
+    let sc = new_sc(&mut sim, 100, 1000);
+    sc.sched_context_bind_tcb(tcb);
+    assert_eq!(tcb.tcbState.get_tcbQueued(), 1);
+
+    // the TCB may not leave the core of its SC
+    sc.scCore = 1;
+    assert_eq!(
+        tcb.set_affinity(0),
+        Err(AffinityError::SchedContextOnOtherCore)
+    );
+    assert_eq!(tcb.tcbState.get_tcbQueued(), 1);
+
+    sc.scCore = 0;
+    assert_eq!(tcb.set_affinity(0), Ok(()));
+    assert_eq!(tcb.tcbAffinity, 0);
+    assert_eq!(tcb.tcbState.get_tcbQueued(), 1);
+}
+
+#[test]
+fn configure_updates_or_rebuilds_the_refills() {
+    use sel4_common::sel4_config::CONFIG_MAX_NUM_NODES;
+    use sel4_task::sched_context::ConfigureError;
+
+    let mut sim = Sim::new();
+    let sc = sim.arena.new_sched_context(8);
+    assert_eq!(
+        sc.sched_context_configure(CONFIG_MAX_NUM_NODES, 0, 100, 1000, 8),
+        Err(ConfigureError::InvalidCore)
+    );
+    assert!(!sc.sc_active());
+
+    // an inactive SC gets new refills
+    SET_NODE_STATE!(ksCurTime = 40);
+    assert_eq!(sc.sched_context_configure(0, 0, 100, 1000, 8), Ok(()));
+    assert_eq!((sc.refill_size(), head(sc)), (1, (40, 100)));
+    let tcb = runnable(&mut sim, 10);
+    sc.sched_context_bind_tcb(tcb);
+
+    // an active SC keeps its head refill, and the extra budget comes a period later
+    SET_NODE_STATE!(ksCurTime = 70);
+    assert_eq!(sc.sched_context_configure(0, 0, 300, 1000, 8), Ok(()));
+    assert_eq!(head(sc), (70, 100));
+    assert_eq!(tail(sc), (1070, 200));
+    assert_eq!(sc.scPeriod, 1000);
+    schedule();
+    assert_eq!(get_currenct_thread().get_ptr(), tcb.get_ptr());
+
+    assert_eq!(sc.sched_context_configure(0, 0, 500, 500, 8), Ok(()));
+    assert!(sc.is_round_robin());
+    assert_eq!(sc.refill_sum(), 500);
+    assert_eq!(
+        NODE_STATE!(ksSchedulerAction),
+        sel4_task::SCHEDULER_ACTION_CHOOSE_NEW_THREAD
+    );
+}
+
+#[test]
+fn configure_rebuilds_the_refills_of_a_blocked_tcb() {
+    let mut sim = Sim::new();
+    let sc = sim.arena.new_sched_context(8);
+    SET_NODE_STATE!(ksCurTime = 40);
+    assert_eq!(sc.sched_context_configure(0, 0, 100, 1000, 8), Ok(()));
+    let tcb = runnable(&mut sim, 10);
+    sc.sched_context_bind_tcb(tcb);
+    set_thread_state(tcb, ThreadState::ThreadStateInactive);
+
+    // the SC is active, but nothing uses its bandwidth, so it starts afresh
+    SET_NODE_STATE!(ksCurTime = 70);
+    assert_eq!(sc.sched_context_configure(0, 0, 300, 1000, 8), Ok(()));
+    assert_eq!((sc.refill_size(), head(sc)), (1, (70, 300)));
+    assert_eq!(tcb.tcbState.get_tcbQueued(), 0);
+}
+
+#[test]
+fn finalise_detaches_the_sched_context() {
+    use sel4_common::structures_gen::call_stack;
+
+    let mut sim = Sim::new();
+    let sc = new_sc(&mut sim, 100, 1000);
+    let tcb = runnable(&mut sim, 10);
+    sc.sched_context_bind_tcb(tcb);
+    let reply = sim.arena.new_reply();
+    reply.replyNext = call_stack::new(1, sc.get_ptr() as u64);
+    sc.scReply = reply.get_ptr();
+
+    sc.sched_context_finalise();
+    assert_eq!((sc.scTcb, tcb.tcbSchedContext, sc.scReply), (0, 0, 0));
+    assert_eq!(tcb.tcbState.get_tcbQueued(), 0);
+    assert_eq!(reply.replyNext.get_callStackPtr(), 0);
+    assert!(!sc.sc_active());
+}
+
+#[test]
+fn set_core_queues_the_tcb_by_its_head_refill() {
+    use sel4_common::sel4_config::CONFIG_MAX_NUM_NODES;
+    use sel4_task::sched_context::ConfigureError;
+
+    let mut sim = Sim::new();
+    let ready = runnable(&mut sim, 10);
+    let ready_sc = new_sc(&mut sim, 100, 1000);
+    ready_sc.sched_context_bind_tcb(ready);
+    assert_eq!(
+        ready_sc.sched_context_set_core(CONFIG_MAX_NUM_NODES),
+        Err(ConfigureError::InvalidCore)
+    );
+
+    // host_sim has a single core: relabelling scCore only exercises the requeue by the head
+    // refill, not a move between cores
+    ready_sc.scCore = 1;
+    ready.tcbAffinity = 1;
+    SET_NODE_STATE!(ksReprogram = false);
+    assert_eq!(ready_sc.sched_context_set_core(0), Ok(()));
+    assert_eq!((ready_sc.scCore, ready.tcbAffinity), (0, 0));
+    assert_eq!(ready.tcbState.get_tcbQueued(), 1);
+    assert_eq!(ready.tcbState.get_tcbInReleaseQueue(), 0);
+    assert!(NODE_STATE!(ksReprogram));
+
+    // an SC waiting for its refill keeps its release time on the new core
+    let waiting = runnable(&mut sim, 10);
+    let waiting_sc = new_sc(&mut sim, 100, 1000);
+    unsafe { (*waiting_sc.refill_head()).rTime = 500 };
+    waiting_sc.sched_context_bind_tcb(waiting);
+    assert_eq!(waiting.tcbState.get_tcbInReleaseQueue(), 1);
+    waiting_sc.scCore = 1;
+    waiting.tcbAffinity = 1;
+    assert_eq!(waiting_sc.sched_context_set_core(0), Ok(()));
+    assert_eq!((waiting_sc.scCore, waiting.tcbAffinity), (0, 0));
+    assert_eq!(waiting.tcbState.get_tcbQueued(), 0);
+    assert_eq!(waiting.tcbState.get_tcbInReleaseQueue(), 1);
+    assert_eq!(head(waiting_sc), (500, 100));
+}
@@ -0,0 +1,962 @@
+//! Scheduler tests for the non-MCS kernel, run against the `host_sim` backend.
+#![cfg(all(feature = "host_sim", not(feature = "kernel_mcs")))]
+
+use sel4_common::sel4_config::{CONFIG_NUM_DOMAINS, CONFIG_TIME_SLICE};
+use sel4_task::host_sim::{ready_queue, Sim};
+use sel4_task::{
+    dschedule_t, get_currenct_thread, get_current_domain, get_idle_thread, is_highest_prio,
+    possible_switch_to, reschedule_required, schedule, set_domain_schedule, set_thread_state,
+    set_time_slice_band, tcb_t, time_slice_band, DomScheduleError, ThreadState, TimeSliceError,
+    NODE_STATE, SCHEDULER_ACTION_CHOOSE_NEW_THREAD, SCHEDULER_ACTION_RESUME_CURRENT_THREAD,
+    TIME_SLICE_BANDS,
+};
+
+fn runnable(sim: &mut Sim, prio: usize, dom: usize) -> &'static mut tcb_t {
+    let tcb = sim.new_thread(prio, dom);
+    set_thread_state(tcb, ThreadState::ThreadStateRunning);
+    tcb
+}
+
+fn current() -> usize {
+    get_currenct_thread().get_ptr()
+}
+
+#[test]
+fn sched_enqueue_updates_queue_and_bitmap() {
+    let mut sim = Sim::new();
+    let a = runnable(&mut sim, 10, 0);
+    let b = runnable(&mut sim, 10, 0);
+
+    a.sched_enqueue();
+    b.sched_enqueue();
+    let queue = ready_queue(0, 10);
+    assert_eq!(queue.head, b.get_ptr());
+    assert_eq!(queue.tail, a.get_ptr());
+    assert_eq!(a.tcbState.get_tcbQueued(), 1);
+    assert!(is_highest_prio(0, 10));
+    assert!(!is_highest_prio(0, 9));
+
+    a.sched_dequeue();
+    b.sched_dequeue();
+    assert!(ready_queue(0, 10).empty());
+    assert_eq!(b.tcbState.get_tcbQueued(), 0);
+    assert!(is_highest_prio(0, 0));
+}
+
+#[test]
+fn sched_append_adds_to_tail() {
+    let mut sim = Sim::new();
+    let a = runnable(&mut sim, 3, 0);
+    let b = runnable(&mut sim, 3, 0);
+
+    a.sched_enqueue();
+    b.sched_append();
+    let queue = ready_queue(0, 3);
+    assert_eq!(queue.head, a.get_ptr());
+    assert_eq!(queue.tail, b.get_ptr());
+    assert_eq!(b.tcbSchedPrev, a.get_ptr());
+    assert_eq!(a.tcbSchedNext, b.get_ptr());
+}
+
+#[test]
+fn schedule_picks_highest_priority() {
+    let mut sim = Sim::new();
+    let low = runnable(&mut sim, 5, 0);
+    let high = runnable(&mut sim, 20, 0);
+
+    low.sched_enqueue();
+    high.sched_enqueue();
+    reschedule_required();
+    schedule();
+
+    assert_eq!(current(), high.get_ptr());
+    assert_eq!(high.tcbState.get_tcbQueued(), 0);
+    assert_eq!(ready_queue(0, 5).head, low.get_ptr());
+    assert_eq!(
+        NODE_STATE!(ksSchedulerAction),
+        SCHEDULER_ACTION_RESUME_CURRENT_THREAD
+    );
+}
+
+#[test]
+fn schedule_falls_back_to_idle() {
+    let _sim = Sim::new();
+    reschedule_required();
+    schedule();
+    assert_eq!(current(), get_idle_thread().get_ptr());
+}
+
+#[test]
+fn possible_switch_to_preempts_lower_priority() {
+    let mut sim = Sim::new();
+    let low = runnable(&mut sim, 5, 0);
+    let high = runnable(&mut sim, 20, 0);
+
+    low.sched_enqueue();
+    reschedule_required();
+    schedule();
+    assert_eq!(current(), low.get_ptr());
+
+    possible_switch_to(high);
+    assert_eq!(NODE_STATE!(ksSchedulerAction), high.get_ptr());
+    schedule();
+    assert_eq!(current(), high.get_ptr());
+    assert_eq!(ready_queue(0, 5).head, low.get_ptr());
+}
+
+#[test]
+fn possible_switch_to_keeps_higher_priority_current() {
+    let mut sim = Sim::new();
+    let high = runnable(&mut sim, 20, 0);
+    let low = runnable(&mut sim, 5, 0);
+
+    high.sched_enqueue();
+    reschedule_required();
+    schedule();
+
+    possible_switch_to(low);
+    schedule();
+    assert_eq!(current(), high.get_ptr());
+    assert_eq!(ready_queue(0, 5).head, low.get_ptr());
+}
+
+#[test]
+fn timer_tick_round_robins_equal_priority() {
+    let mut sim = Sim::new();
+    let a = runnable(&mut sim, 7, 0);
+    let b = runnable(&mut sim, 7, 0);
+
+    a.sched_enqueue();
+    b.sched_enqueue();
+    reschedule_required();
+    schedule();
+    let first = get_currenct_thread();
+    let second = if first.get_ptr() == a.get_ptr() { b } else { a };
+
+    first.tcbTimeSlice = 2;
+    sim.tick();
+    assert_eq!(first.tcbTimeSlice, 1);
+    assert_eq!(
+        NODE_STATE!(ksSchedulerAction),
+        SCHEDULER_ACTION_RESUME_CURRENT_THREAD
+    );
+
+    sim.tick();
+    assert_eq!(first.tcbTimeSlice, CONFIG_TIME_SLICE);
+    assert_eq!(
+        NODE_STATE!(ksSchedulerAction),
+        SCHEDULER_ACTION_CHOOSE_NEW_THREAD
+    );
+    schedule();
+    assert_eq!(current(), second.get_ptr());
+    assert_eq!(ready_queue(0, 7).head, first.get_ptr());
+}
+
+#[test]
+fn timeslice_lengths_follow_thread_and_band() {
+    let mut sim = Sim::new();
+    let band = time_slice_band(7);
+    assert_eq!(
+        set_time_slice_band(TIME_SLICE_BANDS, 3),
+        Err(TimeSliceError::InvalidBand)
+    );
+    assert_eq!(
+        set_time_slice_band(band, 0),
+        Err(TimeSliceError::ZeroLength)
+    );
+    set_time_slice_band(band, 3).unwrap();
+    let a = runnable(&mut sim, 7, 0);
+    let b = runnable(&mut sim, 7, 0);
+    assert_eq!(a.get_time_slice_length(), 3);
+    b.set_time_slice_length(1);
+    assert_eq!(b.tcbTimeSlice, 1);
+
+    a.sched_append();
+    b.sched_append();
+    reschedule_required();
+    schedule();
+    assert_eq!(current(), a.get_ptr());
+    sim.tick();
+    sim.tick();
+    assert_eq!(a.tcbTimeSlice, 1);
+    sim.tick();
+    assert_eq!(a.tcbTimeSlice, 3);
+    schedule();
+    assert_eq!(current(), b.get_ptr());
+
+    sim.tick();
+    assert_eq!(b.tcbTimeSlice, 1);
+    schedule();
+    assert_eq!(current(), a.get_ptr());
+
+    // a shorter band default cuts the slice of the next append
+    set_time_slice_band(band, 2).unwrap();
+    b.sched_dequeue();
+    b.set_time_slice_length(0);
+    b.tcbTimeSlice = 5;
+    b.sched_append();
+    assert_eq!(b.tcbTimeSlice, 2);
+}
+
+#[test]
+fn set_domain_schedule_rejects_invalid_tables() {
+    let _sim = Sim::new();
+    assert_eq!(set_domain_schedule(&[]), Err(DomScheduleError::Empty));
+    assert_eq!(
+        set_domain_schedule(&[
+            dschedule_t {
+                domain: 0,
+                length: 5
+            },
+            dschedule_t {
+                domain: CONFIG_NUM_DOMAINS,
+                length: 5
+            },
+        ]),
+        Err(DomScheduleError::InvalidDomain(1))
+    );
+    assert_eq!(
+        set_domain_schedule(&[dschedule_t {
+            domain: 0,
+            length: 0
+        }]),
+        Err(DomScheduleError::ZeroLength(0))
+    );
+}
+
+#[test]
+fn domains_rotate_at_their_boundary() {
+    if CONFIG_NUM_DOMAINS < 2 {
+        return;
+    }
+    let mut sim = Sim::new();
+    let t0 = runnable(&mut sim, 10, 0);
+    let t1 = runnable(&mut sim, 10, 1);
+    set_domain_schedule(&[
+        dschedule_t {
+            domain: 0,
+            length: 2,
+        },
+        dschedule_t {
+            domain: 1,
+            length: 2,
+        },
+    ])
+    .unwrap();
+
+    t0.sched_enqueue();
+    t1.sched_enqueue();
+    schedule();
+    assert_eq!(get_current_domain(), 0);
+    assert_eq!(current(), t0.get_ptr());
+
+    sim.tick();
+    sim.tick();
+    schedule();
+    assert_eq!(get_current_domain(), 1);
+    assert_eq!(current(), t1.get_ptr());
+    assert_eq!(ready_queue(0, 10).head, t0.get_ptr());
+}
+
+#[test]
+fn empty_domain_runs_idle_thread() {
+    if CONFIG_NUM_DOMAINS < 2 {
+        return;
+    }
+    let mut sim = Sim::new();
+    let t0 = runnable(&mut sim, 10, 0);
+    set_domain_schedule(&[
+        dschedule_t {
+            domain: 0,
+            length: 1,
+        },
+        dschedule_t {
+            domain: 1,
+            length: 1,
+        },
+    ])
+    .unwrap();
+
+    t0.sched_enqueue();
+    schedule();
+    assert_eq!(current(), t0.get_ptr());
+
+    sim.tick();
+    schedule();
+    assert_eq!(get_current_domain(), 1);
+    assert_eq!(current(), get_idle_thread().get_ptr());
+}
+
+#[cfg(feature = "debug_build")]
+#[test]
+fn scheduler_invariants_hold_after_scheduling() {
+    let mut sim = Sim::new();
+    let a = runnable(&mut sim, 7, 0);
+    let b = runnable(&mut sim, 7, 0);
+    let c = runnable(&mut sim, 70, 0);
+
+    a.sched_enqueue();
+    b.sched_append();
+    c.sched_enqueue();
+    sel4_task::check_scheduler_invariants();
+
+    reschedule_required();
+    schedule();
+    b.sched_dequeue();
+    sel4_task::check_scheduler_invariants();
+}
+
+#[cfg(feature = "debug_build")]
+#[test]
+#[should_panic(expected = "not marked queued")]
+fn scheduler_invariants_catch_unmarked_tcb() {
+    let mut sim = Sim::new();
+    let a = runnable(&mut sim, 7, 0);
+
+    a.sched_enqueue();
+    a.tcbState.set_tcbQueued(0);
+    sel4_task::check_scheduler_invariants();
+}
+
+#[cfg(feature = "benchmark_track_utilisation")]
+#[test]
+fn utilisation_counts_ticks_schedules_and_preemptions() {
+    use sel4_task::{
+        benchmark_get_thread_utilisation, benchmark_reset_core_utilisation,
+        benchmark_reset_thread_utilisation, BENCHMARK_IDLE_LOCALCPU_UTILISATION,
+        BENCHMARK_TCB_NUMBER_PREEMPTIONS, BENCHMARK_TCB_NUMBER_SCHEDULES,
+        BENCHMARK_TCB_UTILISATION, BENCHMARK_TOTAL_UTILISATION,
+    };
+
+    let mut sim = Sim::new();
+    let a = runnable(&mut sim, 7, 0);
+    let b = runnable(&mut sim, 7, 0);
+
+    sim.tick();
+    a.sched_enqueue();
+    b.sched_enqueue();
+    reschedule_required();
+    schedule();
+    let first = get_currenct_thread();
+    let second = if first.get_ptr() == a.get_ptr() { b } else { a };
+
+    first.tcbTimeSlice = 2;
+    sim.tick();
+    sim.tick();
+    schedule();
+    assert_eq!(current(), second.get_ptr());
+
+    let util = benchmark_get_thread_utilisation(first);
+    assert_eq!(util[BENCHMARK_TCB_UTILISATION], 2);
+    assert_eq!(util[BENCHMARK_TCB_NUMBER_SCHEDULES], 1);
+    assert_eq!(util[BENCHMARK_TCB_NUMBER_PREEMPTIONS], 1);
+    assert_eq!(util[BENCHMARK_IDLE_LOCALCPU_UTILISATION], 1);
+    assert_eq!(util[BENCHMARK_TOTAL_UTILISATION], 3);
+    assert_eq!(
+        benchmark_get_thread_utilisation(second)[BENCHMARK_TCB_NUMBER_PREEMPTIONS],
+        0
+    );
+
+    benchmark_reset_thread_utilisation(first);
+    benchmark_reset_core_utilisation(0);
+    let util = benchmark_get_thread_utilisation(first);
+    assert_eq!(util[BENCHMARK_TCB_UTILISATION], 0);
+    assert_eq!(util[BENCHMARK_IDLE_LOCALCPU_UTILISATION], 0);
+    assert_eq!(util[BENCHMARK_TOTAL_UTILISATION], 0);
+}
+
+#[cfg(feature = "sched_trace")]
+#[test]
+fn sched_trace_records_and_drains_events() {
+    use sel4_task::{
+        sched_event_t, sched_trace_drain, sched_trace_lost, sched_trace_snapshot, SchedEventKind,
+        SCHED_TRACE_ENTRIES,
+    };
+
+    let mut sim = Sim::new();
+    let a = runnable(&mut sim, 9, 0);
+    sim.set_time(42);
+
+    a.sched_enqueue();
+    reschedule_required();
+    schedule();
+
+    let mut events = [sched_event_t {
+        timestamp: 0,
+        cpu: 0,
+        kind: SchedEventKind::Enqueue,
+        tcb: 0,
+        prio: 0,
+        arg: 0,
+    }; SCHED_TRACE_ENTRIES];
+    let count = sched_trace_snapshot(0, &mut events);
+    let kinds: Vec<SchedEventKind> = events[..count].iter().map(|e| e.kind).collect();
+    assert!(kinds.starts_with(&[SchedEventKind::Enqueue, SchedEventKind::RescheduleRequired]));
+    assert!(kinds.ends_with(&[SchedEventKind::Switch, SchedEventKind::Dequeue]));
+    let switch: &sched_event_t = &events[count - 2];
+    assert_eq!(
+        (switch.tcb, switch.prio, switch.timestamp),
+        (a.get_ptr(), 9, 42)
+    );
+
+    let mut first = [events[0]; 1];
+    assert_eq!(sched_trace_drain(0, &mut first), 1);
+    assert_eq!(first[0].kind, SchedEventKind::Enqueue);
+    assert_eq!(sched_trace_drain(0, &mut events), count - 1);
+    assert_eq!(sched_trace_drain(0, &mut events), 0);
+
+    for _ in 0..SCHED_TRACE_ENTRIES + 3 {
+        a.sched_enqueue();
+        a.sched_dequeue();
+    }
+    assert_eq!(sched_trace_snapshot(0, &mut events), SCHED_TRACE_ENTRIES);
+    assert_eq!(
+        sched_trace_lost(0),
+        2 * (SCHED_TRACE_ENTRIES + 3) - SCHED_TRACE_ENTRIES
+    );
+}
+
+#[test]
+fn typed_queue_links_are_independent_and_cleared() {
+    use sel4_task::{tcb_queue_t, EPLink};
+
+    let mut sim = Sim::new();
+    let a = runnable(&mut sim, 4, 0);
+    let b = runnable(&mut sim, 4, 0);
+    let c = runnable(&mut sim, 4, 0);
+
+    let mut ep = tcb_queue_t::<EPLink>::new();
+    ep.ep_append(a);
+    ep.ep_append(b);
+    ep.ep_append(c);
+    a.sched_enqueue();
+
+    assert_eq!(ep.len(), 3);
+    assert!(ep.contains(b));
+    assert!(!ready_queue(0, 4).contains(b));
+    let order: Vec<usize> = ep.iter().map(|t| t.get_ptr()).collect();
+    assert_eq!(order, [a.get_ptr(), b.get_ptr(), c.get_ptr()]);
+
+    ep.ep_dequeue(b);
+    assert_eq!((b.tcbEPNext, b.tcbEPPrev), (0, 0));
+    assert_eq!(ep.pop_front().map(|t| t.get_ptr()), Some(a.get_ptr()));
+    assert_eq!(ep.pop_front().map(|t| t.get_ptr()), Some(c.get_ptr()));
+    assert!(ep.pop_front().is_none());
+    assert!(ep.empty() && ep.tail == 0);
+
+    // the scheduler links were left alone
+    assert_eq!(ready_queue(0, 4).len(), 1);
+    a.sched_dequeue();
+    b.tcbSchedPrev = a.get_ptr();
+    b.sched_enqueue();
+    assert_eq!(b.tcbSchedPrev, 0);
+}
+
+#[test]
+fn thread_state_decoding_rejects_corrupted_values() {
+    use sel4_task::InvalidThreadState;
+
+    assert_eq!(
+        ThreadState::try_from(5),
+        Ok(ThreadState::ThreadStateBlockedOnReply)
+    );
+    assert_eq!(ThreadState::try_from(9), Err(InvalidThreadState(9)));
+
+    let mut sim = Sim::new();
+    let tcb = sim.new_thread(1, 0);
+    tcb.tcbState.set_tsType(9);
+    assert_eq!(tcb.try_get_state(), Err(InvalidThreadState(9)));
+}
+
+#[test]
+fn thread_state_transitions() {
+    use ThreadState::*;
+
+    assert!(ThreadStateRunning.can_transition_to(ThreadStateBlockedOnReply));
+    assert!(ThreadStateBlockedOnSend.can_transition_to(ThreadStateBlockedOnReply));
+    assert!(ThreadStateBlockedOnReceive.can_transition_to(ThreadStateRunning));
+    assert!(ThreadStateBlockedOnNotification.can_transition_to(ThreadStateInactive));
+    assert!(ThreadStateInactive.can_transition_to(ThreadStateRestart));
+    assert!(!ThreadStateInactive.can_transition_to(ThreadStateBlockedOnReply));
+    assert!(!ThreadStateBlockedOnReceive.can_transition_to(ThreadStateBlockedOnSend));
+    assert!(!ThreadStateRunning.can_transition_to(ThreadStateIdleThreadState));
+    assert!(!ThreadStateIdleThreadState.can_transition_to(ThreadStateRunning));
+}
+
+#[cfg(feature = "debug_build")]
+#[test]
+#[should_panic(
+    expected = "invalid thread state transition ThreadStateInactive -> ThreadStateBlockedOnReply"
+)]
+fn set_thread_state_rejects_invalid_transition() {
+    let mut sim = Sim::new();
+    let tcb = sim.new_thread(1, 0);
+    set_thread_state(tcb, ThreadState::ThreadStateBlockedOnReply);
+}
+
+#[cfg(feature = "ep_priority_queue")]
+#[test]
+fn ep_append_orders_by_priority() {
+    use sel4_task::{tcb_queue_t, EPLink};
+
+    let mut sim = Sim::new();
+    let low = sim.new_thread(2, 0);
+    let high = sim.new_thread(200, 0);
+    let mid_a = sim.new_thread(50, 0);
+    let mid_b = sim.new_thread(50, 0);
+
+    let mut ep = tcb_queue_t::<EPLink>::new();
+    ep.ep_append(low);
+    ep.ep_append(mid_a);
+    ep.ep_append(high);
+    ep.ep_append(mid_b);
+    let order: Vec<usize> = ep.iter().map(|t| t.get_ptr()).collect();
+    assert_eq!(
+        order,
+        [
+            high.get_ptr(),
+            mid_a.get_ptr(),
+            mid_b.get_ptr(),
+            low.get_ptr()
+        ]
+    );
+    assert_eq!(ep.tail, low.get_ptr());
+}
+
+#[cfg(feature = "priority_inheritance")]
+#[test]
+fn priority_inheritance_propagates_along_call_chain() {
+    let mut sim = Sim::new();
+    let client = runnable(&mut sim, 100, 0);
+    let middle = runnable(&mut sim, 10, 0);
+    let server = runnable(&mut sim, 5, 0);
+    server.sched_enqueue();
+
+    set_thread_state(middle, ThreadState::ThreadStateBlockedOnReply);
+    middle.pi_block_on(server);
+    assert_eq!(server.tcbPriority, 10);
+    assert_eq!(ready_queue(0, 10).head, server.get_ptr());
+
+    set_thread_state(client, ThreadState::ThreadStateBlockedOnReply);
+    client.pi_block_on(middle);
+    assert_eq!(middle.tcbPriority, 100);
+    assert_eq!(server.tcbPriority, 100);
+    assert_eq!(server.base_priority(), 5);
+    assert_eq!(ready_queue(0, 100).head, server.get_ptr());
+    assert!(ready_queue(0, 10).empty());
+
+    // a boosted server keeps its boost across a change of its own priority
+    server.set_priority(7);
+    assert_eq!((server.tcbPriority, server.base_priority()), (100, 7));
+
+    set_thread_state(client, ThreadState::ThreadStateRunning);
+    assert_eq!(client.tcbPIServer, 0);
+    assert_eq!((middle.tcbPriority, server.tcbPriority), (10, 10));
+
+    set_thread_state(middle, ThreadState::ThreadStateRunning);
+    assert_eq!(server.tcbPriority, 7);
+    assert_eq!(ready_queue(0, 7).head, server.get_ptr());
+}
+
+#[cfg(feature = "ep_priority_queue")]
+#[test]
+fn set_priority_reorders_endpoint_queue() {
+    use sel4_task::host_sim::endpoint_queue;
+
+    let mut sim = Sim::new();
+    let ep = sim.arena.new_endpoint();
+    let a = runnable(&mut sim, 100, 0);
+    let b = runnable(&mut sim, 50, 0);
+    let c = runnable(&mut sim, 10, 0);
+    for tcb in [&mut *a, &mut *b, &mut *c] {
+        sim.block_on_endpoint(tcb, ep, ThreadState::ThreadStateBlockedOnSend);
+    }
+    let order = || -> Vec<usize> { endpoint_queue(ep).iter().map(|t| t.get_ptr()).collect() };
+    assert_eq!(order(), [a.get_ptr(), b.get_ptr(), c.get_ptr()]);
+
+    c.set_priority(200);
+    assert_eq!(order(), [c.get_ptr(), a.get_ptr(), b.get_ptr()]);
+    a.set_priority(1);
+    assert_eq!(order(), [c.get_ptr(), b.get_ptr(), a.get_ptr()]);
+    assert_eq!(endpoint_queue(ep).tail, a.get_ptr());
+}
+
+#[cfg(feature = "ep_priority_queue")]
+#[test]
+fn reorder_without_hooks_falls_back_to_the_ipc_crate() {
+    use sel4_task::host_sim::endpoint_queue;
+
+    let mut sim = Sim::new();
+    let ep = sim.arena.new_endpoint();
+    let a = runnable(&mut sim, 100, 0);
+    let b = runnable(&mut sim, 50, 0);
+    for tcb in [&mut *a, &mut *b] {
+        sim.block_on_endpoint(tcb, ep, ThreadState::ThreadStateBlockedOnSend);
+    }
+    sim.drop_ipc_queue_hooks();
+
+    b.set_priority(150);
+    let order: Vec<usize> = endpoint_queue(ep).iter().map(|t| t.get_ptr()).collect();
+    assert_eq!(order, [b.get_ptr(), a.get_ptr()]);
+}
+
+#[cfg(feature = "smp_load_balance")]
+#[test]
+fn ready_thread_count_follows_the_ready_queues() {
+    use sel4_task::{core_load, ready_threads};
+
+    let mut sim = Sim::new();
+    let a = runnable(&mut sim, 10, 0);
+    let b = runnable(&mut sim, 20, 0);
+    assert_eq!(ready_threads(0, 0), 0);
+
+    a.sched_enqueue();
+    b.sched_append();
+    // queueing a queued thread again does not count it twice
+    a.sched_enqueue();
+    assert_eq!(ready_threads(0, 0), 2);
+    b.set_priority(30);
+    assert_eq!(ready_threads(0, 0), 2);
+    // the idle thread is running, so only the queued threads count
+    assert_eq!(core_load(0), 2);
+
+    a.sched_dequeue();
+    a.sched_dequeue();
+    assert_eq!(ready_threads(0, 0), 1);
+    schedule();
+    assert_eq!(current(), b.get_ptr());
+    assert_eq!((ready_threads(0, 0), core_load(0)), (0, 1));
+}
+
+#[cfg(feature = "smp_load_balance")]
+#[test]
+fn single_core_balancing_keeps_threads_in_place() {
+    use sel4_task::{load_balance, pull_work, ready_threads, LOAD_BALANCE_PERIOD};
+
+    let mut sim = Sim::new();
+    let a = runnable(&mut sim, 10, 0);
+    let b = runnable(&mut sim, 10, 0);
+    a.set_pinned(true);
+    assert!(a.tcbPinned);
+    a.set_pinned(false);
+    assert!(!a.tcbPinned);
+
+    a.sched_enqueue();
+    b.sched_enqueue();
+    load_balance();
+    assert!(!pull_work(0));
+    for _ in 0..LOAD_BALANCE_PERIOD {
+        sim.tick();
+    }
+    let queue = ready_queue(0, 10);
+    assert_eq!((queue.head, queue.tail), (b.get_ptr(), a.get_ptr()));
+    assert_eq!(ready_threads(0, 0), 2);
+}
+
+#[cfg(feature = "have_fpu")]
+#[test]
+fn fpu_is_restored_on_first_use_and_released_on_suspend() {
+    use sel4_task::handle_fpu_fault;
+    use sel4_task::host_sim::fpu::{fpu_enabled, fpu_loads_saves};
+
+    let mut sim = Sim::new();
+    let a = runnable(&mut sim, 10, 0);
+    let b = runnable(&mut sim, 10, 0);
+
+    a.switch_to_this();
+    assert!(!fpu_enabled());
+    handle_fpu_fault();
+    assert!(fpu_enabled());
+    assert_eq!(NODE_STATE!(ksActiveFPUState), a.get_ptr());
+    assert_eq!(fpu_loads_saves(), (1, 0));
+
+    // the owner gets the FPU back without a restore
+    b.switch_to_this();
+    assert!(!fpu_enabled());
+    a.switch_to_this();
+    assert!(fpu_enabled());
+    assert_eq!(fpu_loads_saves(), (1, 0));
+
+    b.switch_to_this();
+    handle_fpu_fault();
+    assert_eq!(NODE_STATE!(ksActiveFPUState), b.get_ptr());
+    assert_eq!(fpu_loads_saves(), (2, 1));
+
+    b.suspend();
+    assert_eq!(NODE_STATE!(ksActiveFPUState), 0);
+    assert!(!fpu_enabled());
+    assert_eq!(fpu_loads_saves(), (2, 2));
+}
+
+#[cfg(feature = "have_fpu")]
+#[test]
+fn fpu_contention_switches_eagerly() {
+    use sel4_task::host_sim::fpu::fpu_enabled;
+    use sel4_task::{handle_fpu_fault, FPU_EAGER_RESTORES};
+
+    let mut sim = Sim::new();
+    let a = runnable(&mut sim, 10, 0);
+    let b = runnable(&mut sim, 10, 0);
+
+    // two threads that use the FPU every time they run take it from each other
+    for _ in 0..FPU_EAGER_RESTORES {
+        for thread in [&mut *a, &mut *b] {
+            thread.switch_to_this();
+            if !fpu_enabled() {
+                handle_fpu_fault();
+            }
+        }
+    }
+    assert!(a.fpu_eager() && b.fpu_eager());
+    a.switch_to_this();
+    assert!(fpu_enabled());
+    assert_eq!(NODE_STATE!(ksActiveFPUState), a.get_ptr());
+
+    // once a stops using the FPU its score decays back to lazy switching
+    let mut switches = 0;
+    while a.fpu_eager() {
+        b.switch_to_this();
+        if !fpu_enabled() {
+            handle_fpu_fault();
+        }
+        a.switch_to_this();
+        switches += 1;
+    }
+    assert_eq!(switches, FPU_EAGER_RESTORES - 1);
+    assert!(!fpu_enabled());
+    assert!(b.fpu_eager());
+
+    b.suspend();
+    assert_eq!(b.tcbFpuRestores, 0);
+}
+
+#[test]
+fn fault_without_encoding_is_reported() {
+    use sel4_common::structures_gen::seL4_Fault_tag;
+    use sel4_task::UnknownFault;
+
+    let mut sim = Sim::new();
+    let sender = runnable(&mut sim, 10, 0);
+    let receiver = runnable(&mut sim, 10, 0);
+
+    // a zeroed TCB holds a null fault, which has no message
+    assert_eq!(
+        sender.set_fault_mrs(receiver),
+        Err(UnknownFault(seL4_Fault_tag::seL4_Fault_NullFault as usize))
+    );
+}
+
+#[cfg(feature = "hardware_debug_api")]
+#[test]
+fn debug_exception_fault_messages() {
+    use sel4_common::structures_gen::seL4_Fault_DebugException;
+    use sel4_task::host_sim::arch::ArchReg;
+    use sel4_task::{
+        DEBUG_EXCEPTION_BREAKPOINT_NUMBER, DEBUG_EXCEPTION_EXCEPTION_REASON,
+        DEBUG_EXCEPTION_FAULT_IP, DEBUG_EXCEPTION_TRIGGER_ADDRESS, SINGLE_STEP,
+    };
+
+    let mut sim = Sim::new();
+    let sender = runnable(&mut sim, 10, 0);
+    let receiver = runnable(&mut sim, 10, 0);
+    sender.tcbArch.set_register(ArchReg::FAULT_IP, 0x4000);
+    let mr = |tcb: &tcb_t, i| tcb.tcbArch.get_register(ArchReg::Msg(i));
+
+    // a breakpoint reports its address and number
+    sender.tcbFault = seL4_Fault_DebugException::new(0x8000, 0, 3).unsplay();
+    assert_eq!(sender.set_fault_mrs(receiver), Ok(4));
+    assert_eq!(mr(receiver, DEBUG_EXCEPTION_FAULT_IP), 0x4000);
+    assert_eq!(mr(receiver, DEBUG_EXCEPTION_EXCEPTION_REASON), 0);
+    assert_eq!(mr(receiver, DEBUG_EXCEPTION_TRIGGER_ADDRESS), 0x8000);
+    assert_eq!(mr(receiver, DEBUG_EXCEPTION_BREAKPOINT_NUMBER), 3);
+
+    // a single step only reports the fault IP and the reason
+    let receiver = runnable(&mut sim, 10, 0);
+    sender.tcbFault = seL4_Fault_DebugException::new(0x8000, SINGLE_STEP as u64, 3).unsplay();
+    assert_eq!(sender.set_fault_mrs(receiver), Ok(2));
+    assert_eq!(mr(receiver, DEBUG_EXCEPTION_EXCEPTION_REASON), SINGLE_STEP);
+    assert_eq!(mr(receiver, DEBUG_EXCEPTION_TRIGGER_ADDRESS), 0);
+    assert_eq!(mr(receiver, DEBUG_EXCEPTION_BREAKPOINT_NUMBER), 0);
+}
+
+#[cfg(feature = "arm_hypervisor_support")]
+#[test]
+fn vcpu_fault_messages() {
+    use sel4_common::structures_gen::{
+        seL4_Fault_VCPUFault, seL4_Fault_VGICMaintenance, seL4_Fault_VPPIEvent,
+    };
+    use sel4_task::host_sim::arch::ArchReg;
+    use sel4_task::{VCPU_FAULT_HSR, VGIC_MAINTENANCE_IDX, VPPI_EVENT_IRQ};
+
+    let mut sim = Sim::new();
+    let sender = runnable(&mut sim, 10, 0);
+    let receiver = runnable(&mut sim, 10, 0);
+    let mr = |tcb: &tcb_t, i| tcb.tcbArch.get_register(ArchReg::Msg(i));
+
+    sender.tcbFault = seL4_Fault_VCPUFault::new(0x9200_0046).unsplay();
+    assert_eq!(sender.set_fault_mrs(receiver), Ok(1));
+    assert_eq!(mr(receiver, VCPU_FAULT_HSR), 0x9200_0046);
+
+    sender.tcbFault = seL4_Fault_VPPIEvent::new(27).unsplay();
+    assert_eq!(sender.set_fault_mrs(receiver), Ok(1));
+    assert_eq!(mr(receiver, VPPI_EVENT_IRQ), 27);
+
+    sender.tcbFault = seL4_Fault_VGICMaintenance::new(5, 1).unsplay();
+    assert_eq!(sender.set_fault_mrs(receiver), Ok(1));
+    assert_eq!(mr(receiver, VGIC_MAINTENANCE_IDX), 5);
+
+    // an invalid list register index is reported as -1
+    sender.tcbFault = seL4_Fault_VGICMaintenance::new(5, 0).unsplay();
+    assert_eq!(sender.set_fault_mrs(receiver), Ok(1));
+    assert_eq!(mr(receiver, VGIC_MAINTENANCE_IDX), usize::MAX);
+}
+
+#[cfg(feature = "tickless")]
+#[test]
+fn tickless_timer_only_runs_for_shared_priority() {
+    use sel4_common::platform::time_def::ticks_t;
+    use sel4_task::host_sim::{arch::timer, SIM_TICK_LENGTH};
+
+    // the boundary of another domain would keep the timer running
+    if CONFIG_NUM_DOMAINS > 1 {
+        return;
+    }
+    let mut sim = Sim::new();
+    let a = runnable(&mut sim, 7, 0);
+    let b = runnable(&mut sim, 7, 0);
+
+    a.sched_enqueue();
+    schedule();
+    assert_eq!(current(), a.get_ptr());
+    assert_eq!(timer.deadline(), ticks_t::MAX);
+
+    b.sched_enqueue();
+    schedule();
+    assert_eq!(current(), a.get_ptr());
+    let slice_end = CONFIG_TIME_SLICE * SIM_TICK_LENGTH;
+    assert_eq!(timer.deadline(), slice_end as ticks_t);
+
+    sim.set_time(slice_end);
+    sel4_task::timer_tick();
+    schedule();
+    assert_eq!(current(), b.get_ptr());
+    assert_eq!(a.tcbTimeSlice, CONFIG_TIME_SLICE);
+    assert_eq!(timer.deadline(), (2 * slice_end) as ticks_t);
+
+    a.sched_dequeue();
+    set_thread_state(b, ThreadState::ThreadStateInactive);
+    schedule();
+    assert_eq!(current(), get_idle_thread().get_ptr());
+    assert_eq!(timer.deadline(), ticks_t::MAX);
+}
+
+#[cfg(feature = "debug_build")]
+#[test]
+fn debug_registry_names_and_finds_threads() {
+    use sel4_task::{debug_tcbs, find_tcb_by_name, TCB_NAME_LENGTH};
+
+    let mut sim = Sim::new();
+    let a = sim.new_thread(10, 0);
+    let b = sim.new_thread(20, 0);
+    a.set_name("worker");
+    b.set_name(&"x".repeat(2 * TCB_NAME_LENGTH));
+
+    assert_eq!(get_idle_thread().get_name(), "idle_thread");
+    assert_eq!(b.get_name().len(), TCB_NAME_LENGTH - 1);
+    assert_eq!(
+        find_tcb_by_name("worker").map(|t| t.get_ptr()),
+        Some(a.get_ptr())
+    );
+    let live: Vec<usize> = debug_tcbs(0).map(|t| t.get_ptr()).collect();
+    assert_eq!(
+        live,
+        [b.get_ptr(), a.get_ptr(), get_idle_thread().get_ptr()]
+    );
+
+    a.debug_remove();
+    assert!(find_tcb_by_name("worker").is_none());
+    assert_eq!(debug_tcbs(0).count(), 2);
+}
+
+#[test]
+fn dump_all_threads_logs_every_thread() {
+    use sel4_task::dump_all_threads;
+    use std::sync::Mutex;
+
+    struct Capture(Mutex<Vec<String>>);
+    impl log::Log for Capture {
+        fn enabled(&self, _metadata: &log::Metadata) -> bool {
+            true
+        }
+        fn log(&self, record: &log::Record) {
+            self.0.lock().unwrap().push(record.args().to_string());
+        }
+        fn flush(&self) {}
+    }
+    static CAPTURE: Capture = Capture(Mutex::new(Vec::new()));
+    let _ = log::set_logger(&CAPTURE);
+    log::set_max_level(log::LevelFilter::Info);
+
+    let mut sim = Sim::new();
+    let a = runnable(&mut sim, 10, 0);
+    a.sched_enqueue();
+    CAPTURE.0.lock().unwrap().clear();
+    dump_all_threads();
+
+    let lines = CAPTURE.0.lock().unwrap().clone();
+    let line_of = |tcb: &tcb_t| {
+        lines
+            .iter()
+            .find(|line| line.contains(&format!("tcb {:#x} ", tcb.get_ptr())))
+            .cloned()
+            .unwrap_or_default()
+    };
+    assert!(line_of(a).contains("ThreadStateRunning prio 10 mcp 10 domain 0"));
+    assert!(line_of(get_idle_thread()).contains("ThreadStateIdleThreadState"));
+
+    // a priority without a time slice band is logged with the raw length instead of panicking
+    a.tcbPriority = usize::MAX;
+    CAPTURE.0.lock().unwrap().clear();
+    dump_all_threads();
+    let lines = CAPTURE.0.lock().unwrap().clone();
+    let line = lines
+        .iter()
+        .find(|line| line.contains(&format!("tcb {:#x} ", a.get_ptr())))
+        .unwrap();
+    assert!(line.contains("/0 blocking"));
+    a.tcbPriority = 10;
+}
+
+#[test]
+fn set_affinity_requeues_on_the_new_core() {
+    use sel4_common::sel4_config::CONFIG_MAX_NUM_NODES;
+    use sel4_task::AffinityError;
+
+    let mut sim = Sim::new();
+    let a = runnable(&mut sim, 10, 0);
+    let b = runnable(&mut sim, 10, 0);
+    a.sched_enqueue();
+    b.sched_enqueue();
+    assert_eq!(
+        a.set_affinity(CONFIG_MAX_NUM_NODES),
+        Err(AffinityError::InvalidCore)
+    );
+    let queue = ready_queue(0, 10);
+    assert_eq!((queue.head, queue.tail), (b.get_ptr(), a.get_ptr()));
+
+    // the thread is moved to the tail of the ready queue of its new core
+    assert_eq!(b.set_affinity(0), Ok(()));
+    let queue = ready_queue(0, 10);
+    assert_eq!((queue.head, queue.tail), (a.get_ptr(), b.get_ptr()));
+    assert_eq!(b.tcbAffinity, 0);
+
+    // moving the current thread makes the core choose a new one
+    schedule();
+    assert_eq!(current(), a.get_ptr());
+    assert_eq!(a.set_affinity(0), Ok(()));
+    assert!(ready_queue(0, 10).contains(a));
+    assert_eq!(
+        NODE_STATE!(ksSchedulerAction),
+        SCHEDULER_ACTION_CHOOSE_NEW_THREAD
+    );
+}